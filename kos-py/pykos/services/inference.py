@@ -1,6 +1,9 @@
 """Inference service client."""
 
-from typing import NotRequired, TypedDict
+import hashlib
+import os
+import struct
+from typing import AsyncGenerator, AsyncIterator, NotRequired, TypedDict
 
 import grpc
 import grpc.aio
@@ -41,10 +44,63 @@ class Tensor(TypedDict):
     Args:
         values: Tensor values in row-major order
         shape: List of dimension information
+        dtype: Element type name, e.g. "FLOAT32" or "INT64" (FLOAT32 if not given)
+        raw_data: Little-endian elements in row-major order, sent instead of values
+            when set. Values of received tensors are decoded from it.
     """
 
     values: list[float]
     shape: list[TensorDimension]
+    dtype: NotRequired[str]
+    raw_data: NotRequired[bytes]
+
+
+# struct format character of each element type carried in raw_data.
+_RAW_FORMATS = {
+    "FLOAT32": "f",
+    "FLOAT16": "e",
+    "FLOAT64": "d",
+    "INT8": "b",
+    "INT16": "h",
+    "INT32": "i",
+    "INT64": "q",
+    "UINT8": "B",
+    "UINT16": "H",
+    "UINT32": "I",
+    "UINT64": "Q",
+    "BOOL": "?",
+}
+
+
+def _tensor_from_proto(tensor: inference_pb2.Tensor) -> Tensor:
+    dtype = inference_pb2.Tensor.DataType.Name(tensor.dtype)
+    values = list(tensor.values)
+    if tensor.raw_data and dtype in _RAW_FORMATS:
+        fmt = _RAW_FORMATS[dtype]
+        count = len(tensor.raw_data) // struct.calcsize(fmt)
+        values = [float(v) for v in struct.unpack(f"<{count}{fmt}", tensor.raw_data)]
+    result = Tensor(
+        values=values,
+        shape=[TensorDimension(size=dim.size, name=dim.name, dynamic=dim.dynamic) for dim in tensor.shape],
+        dtype=dtype,
+    )
+    if tensor.raw_data:
+        result["raw_data"] = tensor.raw_data
+    return result
+
+
+def _tensor_to_proto(tensor: Tensor) -> inference_pb2.Tensor:
+    shape = [
+        inference_pb2.Tensor.Dimension(size=dim["size"], name=dim["name"], dynamic=dim["dynamic"])
+        for dim in tensor["shape"]
+    ]
+    raw_data = tensor.get("raw_data", b"")
+    return inference_pb2.Tensor(
+        values=[] if raw_data else tensor["values"],
+        shape=shape,
+        dtype=inference_pb2.Tensor.DataType.Value(tensor.get("dtype", "FLOAT32")),
+        raw_data=raw_data,
+    )
 
 
 class ForwardResponse(TypedDict):
@@ -129,6 +185,103 @@ class InferenceServiceClient(AsyncClientBase):
         request = inference_pb2.UploadModelRequest(model=model_data, metadata=proto_metadata)
         return await self.stub.UploadModel(request)
 
+    async def upload_model_stream(
+        self,
+        chunks: AsyncIterator[bytes],
+        sha256: str,
+        total_size: int,
+        offset: int = 0,
+        metadata: ModelMetadata | None = None,
+    ) -> inference_pb2.UploadModelResponse:
+        """Upload a model in chunks, for models too large for a single message.
+
+        If the upload is cut short, the response carries an error and the number of
+        bytes the robot has kept in `received_size`; upload again from that offset to
+        resume.
+
+        Args:
+            chunks: Iterator yielding the model data from `offset` on.
+            sha256: Hex SHA-256 of the whole model.
+            total_size: Size of the whole model in bytes.
+            offset: Offset of the first chunk (0 unless resuming).
+            metadata: Optional metadata about the model.
+
+        Returns:
+            UploadModelResponse containing the model UID, the bytes received and any
+            error information.
+        """
+        proto_metadata = None
+        if metadata is not None:
+            proto_metadata = inference_pb2.ModelMetadata(**metadata)
+
+        async def request_iterator() -> AsyncGenerator[inference_pb2.UploadModelStreamRequest, None]:
+            # First message includes the header
+            yield inference_pb2.UploadModelStreamRequest(
+                header=inference_pb2.UploadModelHeader(
+                    metadata=proto_metadata,
+                    total_size=total_size,
+                    sha256=sha256,
+                    offset=offset,
+                ),
+            )
+            async for chunk in chunks:
+                yield inference_pb2.UploadModelStreamRequest(data=chunk)
+
+        return await self.stub.UploadModelStream(request_iterator())
+
+    async def upload_model_file(
+        self,
+        path: str | os.PathLike[str],
+        chunk_size: int = 1024 * 1024,
+        metadata: ModelMetadata | None = None,
+        max_attempts: int = 5,
+    ) -> inference_pb2.UploadModelResponse:
+        """Upload a model file in chunks, resuming where the robot left off.
+
+        The first attempt asks the robot how much of the model it already has, so an
+        upload interrupted earlier continues instead of starting over. Each retry
+        continues from the `received_size` the robot returned.
+
+        Args:
+            path: Path of the model file.
+            chunk_size: Bytes sent per message.
+            metadata: Optional metadata about the model.
+            max_attempts: Uploads tried before giving up.
+
+        Returns:
+            The last UploadModelResponse.
+        """
+        total_size = os.path.getsize(path)
+        digest = hashlib.sha256()
+        with open(path, "rb") as f:
+            while chunk := f.read(chunk_size):
+                digest.update(chunk)
+        sha256 = digest.hexdigest()
+
+        async def file_chunks(offset: int) -> AsyncGenerator[bytes, None]:
+            with open(path, "rb") as f:
+                f.seek(offset)
+                while chunk := f.read(chunk_size):
+                    yield chunk
+
+        # An offset past what the robot has makes it report what it has.
+        offset = total_size
+        for attempt in range(max_attempts):
+            try:
+                response = await self.upload_model_stream(
+                    file_chunks(offset), sha256, total_size, offset=offset, metadata=metadata
+                )
+            except grpc.aio.AioRpcError as e:
+                retryable = e.code() in (grpc.StatusCode.UNAVAILABLE, grpc.StatusCode.DEADLINE_EXCEEDED)
+                if not retryable or attempt + 1 == max_attempts:
+                    raise
+                offset = total_size
+                continue
+
+            if not response.HasField("error") or response.received_size == offset:
+                return response
+            offset = response.received_size
+        return response
     async def load_models(self, uids: list[str]) -> inference_pb2.LoadModelsResponse:
         """Load models from the robot's filesystem.
 
@@ -153,6 +306,18 @@ class InferenceServiceClient(AsyncClientBase):
         request = inference_pb2.ModelUids(uids=uids)
         return await self.stub.UnloadModels(request)
 
+    async def delete_model(self, uid: str) -> common_pb2.ActionResponse:
+        """Delete a stored model, unloading it first if it is loaded.
+
+        Args:
+            uid: UID of the model to delete.
+
+        Returns:
+            ActionResponse indicating success/failure of the deletion.
+        """
+        request = inference_pb2.DeleteModelRequest(model_uid=uid)
+        return await self.stub.DeleteModel(request)
+
     async def get_models_info(self, model_uids: list[str] | None = None) -> GetModelsInfoResponse:
         """Get information about available models.
 
@@ -186,26 +351,8 @@ class InferenceServiceClient(AsyncClientBase):
                         ),
                         model_author=model.metadata.model_author if model.metadata.HasField("model_author") else None,
                     ),
-                    input_specs={
-                        name: Tensor(
-                            values=list(tensor.values),
-                            shape=[
-                                TensorDimension(size=dim.size, name=dim.name, dynamic=dim.dynamic)
-                                for dim in tensor.shape
-                            ],
-                        )
-                        for name, tensor in model.input_specs.items()
-                    },
-                    output_specs={
-                        name: Tensor(
-                            values=list(tensor.values),
-                            shape=[
-                                TensorDimension(size=dim.size, name=dim.name, dynamic=dim.dynamic)
-                                for dim in tensor.shape
-                            ],
-                        )
-                        for name, tensor in model.output_specs.items()
-                    },
+                    input_specs={name: _tensor_from_proto(tensor) for name, tensor in model.input_specs.items()},
+                    output_specs={name: _tensor_from_proto(tensor) for name, tensor in model.output_specs.items()},
                     description=model.description,
                 )
                 for model in response.models
@@ -213,36 +360,112 @@ class InferenceServiceClient(AsyncClientBase):
             error=response.error if response.HasField("error") else None,
         )
 
-    async def forward(self, model_uid: str, inputs: dict[str, Tensor]) -> ForwardResponse:
+    async def forward(
+        self,
+        model_uid: str,
+        inputs: dict[str, Tensor],
+        raw_outputs: bool = False,
+        session_id: str = "",
+    ) -> ForwardResponse:
         """Run inference using a specified model.
 
         Args:
-            model_uid: The UID of the model to use for inference.
-            inputs: Dictionary mapping tensor names to tensors.
+            model_uid: The UID of the model to use for inference. May be empty with a session.
+            inputs: Dictionary mapping tensor names to tensors. Tensors of types other
+                than FLOAT32 need `dtype` and `raw_data`.
+            raw_outputs: Have every output sent in raw_data, FLOAT32 ones included.
+                Values are decoded from it either way.
+            session_id: Session whose state tensors are fed in and updated. State
+                inputs and outputs are kept on the robot and left out.
 
         Returns:
             ForwardResponse containing:
                 outputs: Dictionary mapping tensor names to output tensors
                 error: Optional error information if inference failed
         """
-        tensor_inputs = {}
-        for name, tensor in inputs.items():
-            shape = [
-                inference_pb2.Tensor.Dimension(size=dim["size"], name=dim["name"], dynamic=dim["dynamic"])
-                for dim in tensor["shape"]
-            ]
-            proto_tensor = inference_pb2.Tensor(values=tensor["values"], shape=shape)
-            tensor_inputs[name] = proto_tensor
-
-        response = await self.stub.Forward(inference_pb2.ForwardRequest(model_uid=model_uid, inputs=tensor_inputs))
+        tensor_inputs = {name: _tensor_to_proto(tensor) for name, tensor in inputs.items()}
+
+        response = await self.stub.Forward(
+            inference_pb2.ForwardRequest(
+                model_uid=model_uid,
+                inputs=tensor_inputs,
+                raw_outputs=raw_outputs,
+                session_id=session_id,
+            )
+        )
 
         return ForwardResponse(
-            outputs={
-                name: Tensor(
-                    values=list(tensor.values),
-                    shape=[TensorDimension(size=dim.size, name=dim.name, dynamic=dim.dynamic) for dim in tensor.shape],
-                )
-                for name, tensor in response.outputs.items()
-            },
+            outputs={name: _tensor_from_proto(tensor) for name, tensor in response.outputs.items()},
             error=response.error if response.HasField("error") else None,
         )
+
+    async def create_session(
+        self,
+        model_uid: str,
+        state_tensors: dict[str, str],
+        dim_sizes: dict[str, int] | None = None,
+    ) -> inference_pb2.CreateSessionResponse:
+        """Create a session that keeps a recurrent model's state on the robot.
+
+        Args:
+            model_uid: UID of the model the session runs.
+            state_tensors: State input name -> output that feeds it on the next step.
+            dim_sizes: Sizes of named dynamic dims in the state (1 if not given).
+
+        Returns:
+            CreateSessionResponse with the session ID to pass to `forward`.
+        """
+        request = inference_pb2.CreateSessionRequest(
+            model_uid=model_uid,
+            state_tensors=state_tensors,
+            dim_sizes=dim_sizes or {},
+        )
+        return await self.stub.CreateSession(request)
+
+    async def reset_session(self, session_id: str) -> common_pb2.ActionResponse:
+        """Reset a session's state tensors to zeros.
+
+        Args:
+            session_id: ID returned by `create_session`.
+
+        Returns:
+            ActionResponse indicating success/failure of the reset.
+        """
+        return await self.stub.ResetSession(inference_pb2.SessionRequest(session_id=session_id))
+
+    async def close_session(self, session_id: str) -> common_pb2.ActionResponse:
+        """Close a session and free its state.
+
+        Args:
+            session_id: ID returned by `create_session`.
+
+        Returns:
+            ActionResponse indicating success/failure of the close.
+        """
+        return await self.stub.CloseSession(inference_pb2.SessionRequest(session_id=session_id))
+
+    async def benchmark(
+        self,
+        model_uid: str,
+        iterations: int = 100,
+        warmup: int = 0,
+        dim_sizes: dict[str, int] | None = None,
+    ) -> inference_pb2.BenchmarkResponse:
+        """Time forward passes of a loaded model with synthetic inputs.
+
+        Args:
+            model_uid: UID of a loaded model.
+            iterations: Timed forward passes (at most 10000).
+            warmup: Untimed forward passes run first (at most 1000).
+            dim_sizes: Sizes of named dynamic dims, e.g. {"batch": 8} (1 if not given).
+
+        Returns:
+            BenchmarkResponse with latency statistics in milliseconds and throughput.
+        """
+        request = inference_pb2.BenchmarkRequest(
+            model_uid=model_uid,
+            iterations=iterations,
+            warmup=warmup,
+            dim_sizes=dim_sizes or {},
+        )
+        return await self.stub.Benchmark(request)
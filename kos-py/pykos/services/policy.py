@@ -3,7 +3,7 @@
 import grpc.aio
 from google.protobuf.empty_pb2 import Empty
 
-from kos_protos import policy_pb2, policy_pb2_grpc
+from kos_protos import common_pb2, policy_pb2, policy_pb2_grpc
 from kos_protos.policy_pb2 import StartPolicyRequest
 from pykos.services import AsyncClientBase
 
@@ -52,3 +52,15 @@ class PolicyServiceClient(AsyncClientBase):
             The response from the server containing the policy state.
         """
         return await self.stub.GetState(request)
+
+    async def set_policy_command(self, commands: dict[str, float]) -> common_pb2.ActionResponse:
+        """Set the commands fed to the running policy, e.g. a velocity target.
+
+        Args:
+            commands: Command values by name, e.g. {"vx": 0.5, "yaw_rate": 0.1}. Replaces
+                the previous commands; names left out read as zero.
+
+        Returns:
+            ActionResponse indicating whether the policy took the commands.
+        """
+        return await self.stub.SetPolicyCommand(policy_pb2.SetPolicyCommandRequest(commands=commands))
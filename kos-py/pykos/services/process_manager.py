@@ -1,9 +1,12 @@
 """Process manager service client."""
 
+from typing import AsyncGenerator
+
 import grpc.aio
+from google.longrunning import operations_pb2, operations_pb2_grpc
 from google.protobuf.empty_pb2 import Empty
 
-from kos_protos import process_manager_pb2, process_manager_pb2_grpc
+from kos_protos import common_pb2, process_manager_pb2, process_manager_pb2_grpc
 from kos_protos.process_manager_pb2 import KClipStartRequest
 from pykos.services import AsyncClientBase
 
@@ -15,6 +18,7 @@ class ProcessManagerServiceClient(AsyncClientBase):
         super().__init__()
 
         self.stub = process_manager_pb2_grpc.ProcessManagerServiceStub(channel)
+        self.operations_stub = operations_pb2_grpc.OperationsStub(channel)
 
     async def start_kclip(self, action: str) -> process_manager_pb2.KClipStartResponse:
         """Start KClip recording.
@@ -35,3 +39,112 @@ class ProcessManagerServiceClient(AsyncClientBase):
             The response from the server.
         """
         return await self.stub.StopKClip(request)
+
+    async def list_clips(self) -> process_manager_pb2.ListClipsResponse:
+        """List the clips stored on the robot.
+
+        Returns:
+            The stored clips, oldest first, along with the storage used and the quota.
+        """
+        return await self.stub.ListClips(Empty())
+
+    async def download_clip(self, clip_uuid: str, chunk_size: int | None = None) -> AsyncGenerator[bytes, None]:
+        """Download a finished clip as a KRec file.
+
+        Args:
+            clip_uuid: UUID of the clip to download
+            chunk_size: Optional size of each chunk in bytes
+
+        Yields:
+            Chunks of the KRec file, in order.
+
+        Example:
+            >>> with open('clip.krec', 'wb') as f:
+            ...     async for chunk in client.download_clip(clip_uuid):
+            ...         f.write(chunk)
+        """
+        request = process_manager_pb2.DownloadClipRequest(clip_uuid=clip_uuid, chunk_size=chunk_size)
+        async for response in self.stub.DownloadClip(request):
+            yield response.data
+
+    async def delete_clip(self, clip_uuid: str) -> common_pb2.ActionResponse:
+        """Delete a finished clip and its sidecar files.
+
+        Args:
+            clip_uuid: UUID of the clip to delete
+
+        Returns:
+            ActionResponse indicating success/failure of the deletion.
+        """
+        request = process_manager_pb2.DeleteClipRequest(clip_uuid=clip_uuid)
+        return await self.stub.DeleteClip(request)
+
+    async def annotate_clip(
+        self,
+        clip_uuid: str,
+        task_labels: list[str] | None = None,
+        success: bool | None = None,
+        notes: str = "",
+    ) -> common_pb2.ActionResponse:
+        """Attach labels to a clip, replacing any existing annotation.
+
+        Args:
+            clip_uuid: UUID of the clip to annotate
+            task_labels: Task labels for the clip
+            success: Whether the recorded attempt succeeded
+            notes: Free-form notes
+
+        Returns:
+            ActionResponse indicating success/failure of the annotation.
+        """
+        annotation = process_manager_pb2.ClipAnnotation(task_labels=task_labels or [], success=success, notes=notes)
+        request = process_manager_pb2.AnnotateClipRequest(clip_uuid=clip_uuid, annotation=annotation)
+        return await self.stub.AnnotateClip(request)
+
+    async def replay_clip(
+        self,
+        clip_uuid: str | None = None,
+        path: str | None = None,
+        speed: float = 1.0,
+        dry_run: bool = False,
+        actuator_ids: list[int] | None = None,
+        allow_unknown_fields: bool = False,
+    ) -> str:
+        """Replay a recording's actuator commands at their original timing.
+
+        Args:
+            clip_uuid: UUID of a clip from the clip library
+            path: Path of a KRec file on the robot, used if no clip UUID is given
+            speed: Playback speed multiplier
+            dry_run: Only compare live actuator states against the recorded ones
+            actuator_ids: Actuators to replay (all if empty)
+            allow_unknown_fields: Replay clips that don't record which command fields
+                were set, taking zero fields as unset
+
+        Returns:
+            The name of the replay operation.
+        """
+        request = process_manager_pb2.ReplayClipRequest(
+            clip_uuid=clip_uuid,
+            path=path if clip_uuid is None else None,
+            speed=speed,
+            dry_run=dry_run,
+            actuator_ids=actuator_ids or [],
+            allow_unknown_fields=allow_unknown_fields,
+        )
+        operation = await self.stub.ReplayClip(request)
+        return operation.name
+
+    async def get_replay_status(self, operation_name: str) -> process_manager_pb2.ReplayClipMetadata:
+        """Get the progress of a replay.
+
+        Args:
+            operation_name: Name returned by `replay_clip`
+
+        Returns:
+            The replay metadata, including status and tracking errors.
+        """
+        operation = await self.operations_stub.GetOperation(operations_pb2.GetOperationRequest(name=operation_name))
+        metadata = process_manager_pb2.ReplayClipMetadata()
+        operation.metadata.Unpack(metadata)
+        return metadata
@@ -1,19 +1,21 @@
 mod actuator;
 mod imu;
-mod policy;
-mod process_manager;
 use crate::actuator::StubActuator;
 use crate::imu::StubIMU;
-use crate::policy::StubPolicy;
-use crate::process_manager::StubProcessManager;
 use async_trait::async_trait;
 use kos::hal::Operation;
 use kos::kos_proto::actuator::actuator_service_server::ActuatorServiceServer;
 use kos::kos_proto::imu::imu_service_server::ImuServiceServer;
+use kos::kos_proto::inference::inference_service_server::InferenceServiceServer;
 use kos::kos_proto::policy::policy_service_server::PolicyServiceServer;
 use kos::kos_proto::process_manager::process_manager_service_server::ProcessManagerServiceServer;
+use kos::model_store::ModelStore;
+use kos::onnx::OnnxInference;
+use kos::policy_runner::PolicyRunner;
+use kos::recording::{KClipConfig, KClipManager, RecordingSources};
 use kos::services::{
-    ActuatorServiceImpl, IMUServiceImpl, PolicyServiceImpl, ProcessManagerServiceImpl,
+    ActuatorServiceImpl, IMUServiceImpl, InferenceServiceImpl, PolicyServiceImpl,
+    ProcessManagerServiceImpl,
 };
 use kos::{services::OperationsServiceImpl, Platform, ServiceEnum};
 
@@ -55,22 +57,42 @@ impl Platform for StubPlatform {
         operations_service: Arc<OperationsServiceImpl>,
     ) -> Pin<Box<dyn Future<Output = eyre::Result<Vec<ServiceEnum>>> + Send + 'a>> {
         Box::pin(async move {
-            let actuator = StubActuator::new(operations_service.clone());
-            let imu = StubIMU::new(operations_service.clone());
-            let process_manager = StubProcessManager::new();
-            let policy = StubPolicy::new();
+            let actuator = Arc::new(StubActuator::new(operations_service.clone()));
+            let imu = Arc::new(StubIMU::new(operations_service.clone()));
+            let inference = Arc::new(OnnxInference::new(ModelStore::open_default()?));
+            let policy = Arc::new(PolicyRunner::new(
+                inference.clone(),
+                imu.clone(),
+                actuator.clone(),
+            ));
+            let process_manager = KClipManager::new(
+                self.name(),
+                self.serial(),
+                KClipConfig::default(),
+                RecordingSources {
+                    actuator: Some(actuator.clone()),
+                    policy: Some(policy.clone()),
+                    inference: Some(inference.clone()),
+                    policy_config_dir: Some(policy.config_dir().to_path_buf()),
+                    ..Default::default()
+                },
+            )?
+            .with_operations(operations_service.clone());
 
             Ok(vec![
                 ServiceEnum::Actuator(ActuatorServiceServer::new(ActuatorServiceImpl::new(
-                    Arc::new(actuator),
+                    actuator,
                 ))),
                 ServiceEnum::ProcessManager(ProcessManagerServiceServer::new(
                     ProcessManagerServiceImpl::new(Arc::new(process_manager)),
                 )),
-                ServiceEnum::Imu(ImuServiceServer::new(IMUServiceImpl::new(Arc::new(imu)))),
+                ServiceEnum::Imu(ImuServiceServer::new(IMUServiceImpl::new(imu))),
+                ServiceEnum::Inference(InferenceServiceServer::new(InferenceServiceImpl::new(
+                    inference,
+                ))),
                 ServiceEnum::Policy(PolicyServiceServer::new(
                     // Add this block
-                    PolicyServiceImpl::new(Arc::new(policy)),
+                    PolicyServiceImpl::new(policy),
                 )),
             ])
         })
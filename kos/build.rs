@@ -20,6 +20,7 @@ fn main() {
         "kos/system.proto",
         "kos/led_matrix.proto",
         "kos/sound.proto",
+        "kos/telemetry.proto",
         "google/longrunning/operations.proto",
     ];
 
@@ -28,10 +29,27 @@ fn main() {
     // Create the output directory
     std::fs::create_dir_all(out_dir.join("kos")).expect("Failed to create output directory");
 
+    // Telemetry events are published as JSON/CBOR too, so they need serde
+    let events = [
+        "kos.telemetry.ModelEvent",
+        "kos.telemetry.ForwardEvent",
+        "kos.telemetry.PolicyEvent",
+        "kos.telemetry.AudioEvent",
+        "kos.telemetry.LedEvent",
+    ];
+
     // Configure and compile Protobuf files
-    tonic_build::configure()
+    let mut config = tonic_build::configure();
+    for event in events {
+        config = config.type_attribute(
+            event,
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default)]",
+        );
+    }
+    config
         .build_server(true)
         .out_dir(out_dir.join("kos"))
+        .file_descriptor_set_path(out_dir.join("kos_descriptor.bin"))
         .protoc_arg("--experimental_allow_proto3_optional")
         .compile_protos(&protos, &includes)
         .expect("Failed to compile protos");
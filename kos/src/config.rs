@@ -1,3 +1,15 @@
 // TODO: Implement config loading.
 // Config should include embodiment information (e.g. limb names, actuator names),
 // as well as hardware parameters (e.g. serial port names, motor types, PID gains).
+
+use directories::BaseDirs;
+use std::path::PathBuf;
+
+/// Root directory for everything the daemon persists (logs, clips, models).
+pub fn kos_data_dir() -> PathBuf {
+    if let Some(base_dirs) = BaseDirs::new() {
+        base_dirs.data_local_dir().join("kos")
+    } else {
+        PathBuf::from("~/.local/share/kos")
+    }
+}
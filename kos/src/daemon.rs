@@ -1,16 +1,24 @@
 use crate::file_logging::{cleanup_logging, setup_logging};
 use crate::google_proto::longrunning::operations_server::OperationsServer;
+use crate::metrics::{serve_metrics, RpcMetricsLayer};
+use crate::recording::{default_export_path, export, ExportFormat};
 use crate::services::OperationsServiceImpl;
-use crate::telemetry::Telemetry;
+use crate::telemetry::{MqttConfig, MqttTlsConfig, Telemetry, TelemetryConfig};
+use crate::telemetry_encoding::TelemetryEncoding;
+use crate::telemetry_influx::{InfluxConfig, InfluxTarget};
+use crate::telemetry_spool::SpoolConfig;
 use crate::Platform;
 use crate::ServiceEnum;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use eyre::Result;
+use rumqttc::QoS;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::signal;
 use tokio::sync::Mutex;
 use tonic::transport::Server;
+use tower::util::option_layer;
 use tracing::{debug, error, info};
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::prelude::*;
@@ -26,12 +34,113 @@ struct Args {
     /// Log level (trace, debug, info, warn, error)
     #[arg(long, default_value = "info")]
     log_level: String,
+
+    /// Disable the MQTT telemetry sink (telemetry is still available in-process)
+    #[arg(long, default_value_t = false)]
+    no_mqtt: bool,
+
+    /// MQTT broker host
+    #[arg(long, default_value = "localhost")]
+    mqtt_host: String,
+
+    /// MQTT broker port
+    #[arg(long, default_value_t = 1883)]
+    mqtt_port: u16,
+
+    /// MQTT username
+    #[arg(long, env = "KOS_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+
+    /// MQTT password, used with --mqtt-username
+    #[arg(
+        long,
+        env = "KOS_MQTT_PASSWORD",
+        hide_env_values = true,
+        requires = "mqtt_username"
+    )]
+    mqtt_password: Option<String>,
+
+    /// CA certificate (PEM) for connecting to the MQTT broker over TLS
+    #[arg(long)]
+    mqtt_ca_cert: Option<PathBuf>,
+
+    /// Client certificate (PEM) for mutual TLS, used with --mqtt-client-key
+    #[arg(long, requires = "mqtt_ca_cert")]
+    mqtt_client_cert: Option<PathBuf>,
+
+    /// Client private key (PEM) for mutual TLS
+    #[arg(long, requires = "mqtt_client_cert")]
+    mqtt_client_key: Option<PathBuf>,
+
+    /// MQTT QoS level for telemetry (0, 1 or 2)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    mqtt_qos: u8,
+
+    /// Size of the on-disk spool for telemetry that couldn't be sent to MQTT,
+    /// in MiB (0 disables spooling)
+    #[arg(long, default_value_t = 64)]
+    telemetry_spool_mb: u64,
+
+    /// Also write telemetry as InfluxDB line protocol to a file path,
+    /// udp://HOST:PORT or an http:// write URL
+    #[arg(long)]
+    influx_target: Option<InfluxTarget>,
+
+    /// InfluxDB API token for HTTP targets
+    #[arg(long, env = "KOS_INFLUX_TOKEN", hide_env_values = true)]
+    influx_token: Option<String>,
+
+    /// Telemetry payload encoding
+    #[arg(long, value_enum, default_value_t = TelemetryEncoding::Json)]
+    telemetry_encoding: TelemetryEncoding,
+
+    /// Maximum MQTT/InfluxDB publish rate as TOPIC=HZ (e.g. actuator/state=50, or *=100
+    /// for every topic). Can be repeated.
+    #[arg(long = "telemetry-rate-limit", value_parser = parse_rate_limit)]
+    telemetry_rate_limits: Vec<(String, f64)>,
+
+    /// Serve Prometheus metrics on this port at /metrics
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a KRec recording (or crash journal) to CSV or MCAP and exit
+    Export {
+        /// Recording to convert
+        input: PathBuf,
+
+        /// Output directory for CSV, or output file for MCAP
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Mcap)]
+        format: ExportFormat,
+    },
+}
+
+fn parse_rate_limit(value: &str) -> Result<(String, f64), String> {
+    let (topic, hz) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected TOPIC=HZ, got {}", value))?;
+    let hz: f64 = hz
+        .parse()
+        .map_err(|e| format!("invalid rate {}: {}", hz, e))?;
+    if !(hz.is_finite() && hz > 0.0) {
+        return Err(format!("rate must be positive, got {}", hz));
+    }
+    Ok((topic.to_string(), hz))
 }
 
-fn add_service_to_router(
-    router: tonic::transport::server::Router,
+fn add_service_to_router<L>(
+    router: tonic::transport::server::Router<L>,
     service: ServiceEnum,
-) -> tonic::transport::server::Router {
+) -> tonic::transport::server::Router<L> {
     debug!("Adding service to router: {:?}", service);
     match service {
         ServiceEnum::Actuator(svc) => router.add_service(svc),
@@ -47,9 +156,12 @@ fn add_service_to_router(
 async fn run_server(
     platform: &(dyn Platform + Send + Sync),
     operations_service: Arc<OperationsServiceImpl>,
+    rpc_metrics: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let addr = "0.0.0.0:50051".parse()?;
-    let mut server_builder = Server::builder();
+    // RPC metrics are only collected when something can scrape them.
+    let mut server_builder =
+        Server::builder().layer(option_layer(rpc_metrics.then_some(RpcMetricsLayer)));
 
     let services = platform.create_services(operations_service.clone()).await?;
 
@@ -76,6 +188,19 @@ struct DaemonState {
 pub async fn kos_runtime(platform: Box<dyn Platform>) -> Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Export {
+        input,
+        output,
+        format,
+    }) = args.command
+    {
+        setup_logging(false, &args.log_level)?;
+        let output = output.unwrap_or_else(|| default_export_path(&input, format));
+        export(&input, &output, format)?;
+        info!("Exported {} to {}", input.display(), output.display());
+        return Ok(());
+    }
+
     // tracing
     let subscriber = tracing_subscriber::registry();
 
@@ -112,10 +237,38 @@ pub async fn kos_runtime(platform: Box<dyn Platform>) -> Result<()> {
     });
 
     // Telemetry
+    let mqtt = (!args.no_mqtt).then(|| MqttConfig {
+        host: args.mqtt_host.clone(),
+        port: args.mqtt_port,
+        qos: match args.mqtt_qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        },
+        spool: (args.telemetry_spool_mb > 0).then(|| SpoolConfig {
+            max_bytes: args.telemetry_spool_mb * 1024 * 1024,
+            ..Default::default()
+        }),
+        username: args.mqtt_username.clone(),
+        password: args.mqtt_password.clone(),
+        tls: args.mqtt_ca_cert.clone().map(|ca_cert| MqttTlsConfig {
+            ca_cert,
+            client_cert: args.mqtt_client_cert.clone(),
+            client_key: args.mqtt_client_key.clone(),
+        }),
+        ..Default::default()
+    });
     Telemetry::initialize(
         format!("{}-{}", state.platform.name(), state.platform.serial()).as_str(),
-        "localhost",
-        1883,
+        TelemetryConfig {
+            mqtt,
+            influx: args.influx_target.clone().map(|target| InfluxConfig {
+                token: args.influx_token.clone(),
+                ..InfluxConfig::new(target)
+            }),
+            encoding: args.telemetry_encoding,
+            rate_limits: args.telemetry_rate_limits.iter().cloned().collect(),
+        },
     )
     .await?;
 
@@ -124,8 +277,18 @@ pub async fn kos_runtime(platform: Box<dyn Platform>) -> Result<()> {
 
     state.platform.initialize(operations_service.clone())?;
 
+    if let Some(port) = args.metrics_port {
+        let operations_service = operations_service.clone();
+        tokio::spawn(async move {
+            let addr = ([0, 0, 0, 0], port).into();
+            if let Err(e) = serve_metrics(addr, operations_service).await {
+                error!("Metrics server error: {:?}", e);
+            }
+        });
+    }
+
     tokio::select! {
-        res = run_server(&*state.platform, operations_service) => {
+        res = run_server(&*state.platform, operations_service, args.metrics_port.is_some()) => {
             if let Err(e) = res {
                 error!("Server error: {:?}", e);
                 std::process::exit(1);
@@ -133,6 +296,9 @@ pub async fn kos_runtime(platform: Box<dyn Platform>) -> Result<()> {
         }
         _ = shutdown_rx => {
             info!("Received shutdown signal, cleaning up...");
+            if let Some(telemetry) = Telemetry::get().await {
+                telemetry.shutdown().await;
+            }
             cleanup_logging(state._guard.take());
         }
     }
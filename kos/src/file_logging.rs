@@ -1,5 +1,5 @@
+use crate::config::kos_data_dir;
 use chrono::Local;
-use directories::BaseDirs;
 use eyre::Result;
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -135,11 +135,7 @@ pub fn setup_logging(
     let subscriber = subscriber.with(stdout_layer);
 
     if enable_file_logging {
-        let log_dir = if let Some(base_dirs) = BaseDirs::new() {
-            base_dirs.data_local_dir().join("kos").join("logs")
-        } else {
-            PathBuf::from("~/.local/share/kos/logs")
-        };
+        let log_dir = kos_data_dir().join("logs");
 
         std::fs::create_dir_all(&log_dir)?;
 
@@ -1,4 +1,7 @@
 pub mod kos {
+    /// Encoded `FileDescriptorSet` for every KOS proto and its imports.
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("kos_descriptor");
+
     pub mod actuator {
         tonic::include_proto!("kos/kos.actuator");
     }
@@ -34,6 +37,10 @@ pub mod kos {
     pub mod sound {
         tonic::include_proto!("kos/kos.sound");
     }
+
+    pub mod telemetry {
+        tonic::include_proto!("kos/kos.telemetry");
+    }
 }
 
 pub mod google {
@@ -16,6 +16,14 @@ use tokio::sync::mpsc::Sender;
 // Type alias for the audio stream
 pub type AudioStream = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
 
+// Type alias for streaming a clip file in chunks
+pub type ClipStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+pub struct ClipDownload {
+    pub total_size: u64,
+    pub stream: ClipStream,
+}
+
 #[async_trait]
 pub trait Actuator: Send + Sync {
     async fn command_actuators(&self, commands: Vec<ActuatorCommand>) -> Result<Vec<ActionResult>>;
@@ -53,6 +61,30 @@ pub trait IMU: Send + Sync {
 pub trait ProcessManager: Send + Sync {
     async fn start_kclip(&self, action: String) -> Result<KClipStartResponse>;
     async fn stop_kclip(&self) -> Result<KClipStopResponse>;
+
+    async fn list_clips(&self) -> Result<ListClipsResponse> {
+        eyre::bail!("Listing clips is not supported on this platform")
+    }
+
+    async fn download_clip(&self, _clip_uuid: String, _chunk_size: usize) -> Result<ClipDownload> {
+        eyre::bail!("Downloading clips is not supported on this platform")
+    }
+
+    async fn delete_clip(&self, _clip_uuid: String) -> Result<ActionResponse> {
+        eyre::bail!("Deleting clips is not supported on this platform")
+    }
+
+    async fn annotate_clip(
+        &self,
+        _clip_uuid: String,
+        _annotation: ClipAnnotation,
+    ) -> Result<ActionResponse> {
+        eyre::bail!("Annotating clips is not supported on this platform")
+    }
+
+    async fn replay_clip(&self, _request: ReplayClipRequest) -> Result<Operation> {
+        eyre::bail!("Replaying clips is not supported on this platform")
+    }
 }
 
 #[async_trait]
@@ -66,6 +98,13 @@ pub trait Policy: Send + Sync {
     ) -> Result<StartPolicyResponse>;
     async fn stop_policy(&self) -> Result<StopPolicyResponse>;
     async fn get_state(&self) -> Result<GetStateResponse>;
+
+    async fn set_command(
+        &self,
+        _commands: std::collections::HashMap<String, f32>,
+    ) -> Result<ActionResponse> {
+        eyre::bail!("Policy commands are not supported on this platform")
+    }
 }
 
 #[async_trait]
@@ -86,6 +125,10 @@ pub trait Inference: Send + Sync {
         model_uid: String,
         inputs: std::collections::HashMap<String, Tensor>,
     ) -> Result<ForwardResponse>;
+
+    async fn delete_model(&self, _uid: String) -> Result<ActionResponse> {
+        eyre::bail!("Deleting models is not supported on this platform")
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,85 @@
+//! Sessions keep the state tensors of recurrent models (e.g. LSTM or GRU
+//! carries) on the robot between `Forward` calls, so clients only send the
+//! per-step inputs.
+
+use crate::hal::{ModelInfo, Tensor};
+use crate::tensor::zeros;
+use std::collections::HashMap;
+
+pub struct InferenceSession {
+    pub model_uid: String,
+    /// State input name -> output that feeds it on the next step.
+    state_tensors: HashMap<String, String>,
+    initial: HashMap<String, Tensor>,
+    state: HashMap<String, Tensor>,
+}
+
+impl InferenceSession {
+    /// Checks `state_tensors` against the model's specs. State starts out
+    /// zeroed, with dynamic dims sized from `dim_sizes` (1 by default).
+    pub fn new(
+        info: &ModelInfo,
+        state_tensors: HashMap<String, String>,
+        dim_sizes: &HashMap<String, u32>,
+    ) -> Result<Self, String> {
+        if state_tensors.is_empty() {
+            return Err("A session needs at least one state tensor".to_string());
+        }
+
+        let mut initial = HashMap::new();
+        for (input, output) in &state_tensors {
+            let Some(spec) = info.input_specs.get(input) else {
+                return Err(format!("Model has no input '{}'", input));
+            };
+            if !info.output_specs.is_empty() && !info.output_specs.contains_key(output) {
+                return Err(format!("Model has no output '{}'", output));
+            }
+            initial.insert(input.clone(), zeros(spec, dim_sizes));
+        }
+
+        Ok(Self {
+            model_uid: info.uid.clone(),
+            state_tensors,
+            state: initial.clone(),
+            initial,
+        })
+    }
+
+    /// Puts the state back to where the session started.
+    pub fn reset(&mut self) {
+        self.state = self.initial.clone();
+    }
+
+    /// Adds the current state to `inputs`. State inputs can't be sent by
+    /// the client as well.
+    pub fn feed(&self, inputs: &mut HashMap<String, Tensor>) -> Result<(), String> {
+        for (input, tensor) in &self.state {
+            if inputs.contains_key(input) {
+                return Err(format!(
+                    "Input '{}' is session state and can't be passed to Forward",
+                    input
+                ));
+            }
+            inputs.insert(input.clone(), tensor.clone());
+        }
+        Ok(())
+    }
+
+    /// Moves the new state out of `outputs`. The state is left untouched if
+    /// any state output is missing.
+    pub fn update(&mut self, outputs: &mut HashMap<String, Tensor>) -> Result<(), String> {
+        if let Some(output) = self
+            .state_tensors
+            .values()
+            .find(|output| !outputs.contains_key(*output))
+        {
+            return Err(format!("Model did not return state output '{}'", output));
+        }
+        for (input, output) in &self.state_tensors {
+            if let Some(tensor) = outputs.remove(output) {
+                self.state.insert(input.clone(), tensor);
+            }
+        }
+        Ok(())
+    }
+}
@@ -6,9 +6,22 @@ pub mod daemon;
 pub mod file_logging;
 mod grpc_interface;
 pub mod hal;
+pub mod inference_session;
+pub mod metrics;
+pub mod model_store;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod policy_config;
+pub mod policy_runner;
+pub mod recording;
 pub mod services;
 pub mod telemetry;
+pub mod telemetry_encoding;
+pub mod telemetry_influx;
+pub mod telemetry_spool;
 pub mod telemetry_types;
+pub mod tensor;
+pub mod time_sync;
 
 pub use grpc_interface::google as google_proto;
 pub use grpc_interface::kos as kos_proto;
@@ -0,0 +1,323 @@
+//! Prometheus metrics for the daemon.
+//!
+//! Metrics are collected in a process-wide registry and served as text on
+//! `GET /metrics` when the daemon is started with `--metrics-port`. RPC
+//! counters and latencies come from `RpcMetricsLayer`, which wraps the tonic
+//! server; the services record actuator and IMU samples as they read them;
+//! telemetry and operation counts are sampled when the endpoint is scraped.
+
+use crate::hal::ActuatorStateResponse;
+use crate::services::OperationsServiceImpl;
+use crate::telemetry::Telemetry;
+use eyre::Result;
+use futures::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http;
+use tower::{Layer, Service};
+
+/// Latency buckets in seconds, from sub-millisecond control calls up to slow
+/// calibration and model loading requests.
+const RPC_LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    rpc_requests: IntCounterVec,
+    rpc_latency: HistogramVec,
+    actuator_online: IntGaugeVec,
+    actuator_state: GaugeVec,
+    imu_samples: IntCounterVec,
+    telemetry_messages: IntGaugeVec,
+    telemetry_spool_bytes: IntGauge,
+    telemetry_connected: IntGauge,
+    operations: IntGaugeVec,
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::new().expect("Failed to register metrics");
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("kos".to_string()), None)?;
+
+        let rpc_requests = IntCounterVec::new(
+            Opts::new("rpc_requests_total", "gRPC requests by method and status"),
+            &["service", "method", "code"],
+        )?;
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("rpc_duration_seconds", "gRPC request latency")
+                .buckets(RPC_LATENCY_BUCKETS.to_vec()),
+            &["service", "method"],
+        )?;
+        let actuator_online = IntGaugeVec::new(
+            Opts::new(
+                "actuator_online",
+                "Whether the actuator responded when last read",
+            ),
+            &["actuator_id"],
+        )?;
+        let actuator_state = GaugeVec::new(
+            Opts::new(
+                "actuator_state",
+                "Last actuator state returned by GetActuatorsState",
+            ),
+            &["actuator_id", "field"],
+        )?;
+        let imu_samples = IntCounterVec::new(
+            Opts::new("imu_samples_total", "IMU samples read, by kind"),
+            &["kind"],
+        )?;
+        let telemetry_messages = IntGaugeVec::new(
+            Opts::new(
+                "telemetry_messages",
+                "Telemetry messages since startup, by outcome",
+            ),
+            &["outcome"],
+        )?;
+        let telemetry_spool_bytes = IntGauge::new(
+            "telemetry_spool_bytes",
+            "Bytes of telemetry waiting in the spool",
+        )?;
+        let telemetry_connected = IntGauge::new(
+            "telemetry_connected",
+            "Whether the MQTT telemetry sink is connected",
+        )?;
+        let operations = IntGaugeVec::new(
+            Opts::new("operations", "Long-running operations, by type and state"),
+            &["type", "done"],
+        )?;
+
+        registry.register(Box::new(rpc_requests.clone()))?;
+        registry.register(Box::new(rpc_latency.clone()))?;
+        registry.register(Box::new(actuator_online.clone()))?;
+        registry.register(Box::new(actuator_state.clone()))?;
+        registry.register(Box::new(imu_samples.clone()))?;
+        registry.register(Box::new(telemetry_messages.clone()))?;
+        registry.register(Box::new(telemetry_spool_bytes.clone()))?;
+        registry.register(Box::new(telemetry_connected.clone()))?;
+        registry.register(Box::new(operations.clone()))?;
+
+        Ok(Self {
+            registry,
+            rpc_requests,
+            rpc_latency,
+            actuator_online,
+            actuator_state,
+            imu_samples,
+            telemetry_messages,
+            telemetry_spool_bytes,
+            telemetry_connected,
+            operations,
+        })
+    }
+
+    pub fn global() -> &'static Metrics {
+        &METRICS
+    }
+
+    pub fn observe_rpc(&self, path: &str, code: tonic::Code, latency_secs: f64) {
+        let (service, method) = path
+            .trim_start_matches('/')
+            .split_once('/')
+            .unwrap_or(("unknown", path));
+        self.rpc_requests
+            .with_label_values(&[service, method, &format!("{:?}", code)])
+            .inc();
+        self.rpc_latency
+            .with_label_values(&[service, method])
+            .observe(latency_secs);
+    }
+
+    pub fn record_actuator_states(&self, states: &[ActuatorStateResponse]) {
+        for state in states {
+            let id = state.actuator_id.to_string();
+            self.actuator_online
+                .with_label_values(&[&id])
+                .set(state.online as i64);
+
+            let fields = [
+                ("position", state.position),
+                ("velocity", state.velocity),
+                ("torque", state.torque),
+                ("temperature", state.temperature),
+                ("voltage", state.voltage.map(f64::from)),
+                ("current", state.current.map(f64::from)),
+            ];
+            for (field, value) in fields {
+                if let Some(value) = value {
+                    self.actuator_state
+                        .with_label_values(&[&id, field])
+                        .set(value);
+                }
+            }
+        }
+    }
+
+    pub fn record_imu_sample(&self, kind: &str) {
+        self.imu_samples.with_label_values(&[kind]).inc();
+    }
+
+    /// Refreshes the gauges that are sampled rather than recorded as they
+    /// happen.
+    async fn sample(&self, operations: &OperationsServiceImpl) {
+        if let Some(telemetry) = Telemetry::get().await {
+            let stats = telemetry.stats();
+            for (outcome, count) in [
+                ("published", stats.published),
+                ("rate_limited", stats.rate_limited),
+                ("spooled", stats.spooled),
+                ("replayed", stats.replayed),
+                ("dropped", stats.dropped),
+                ("spool_errors", stats.spool_errors),
+                ("influx_dropped", stats.influx_dropped),
+                ("influx_failed", stats.influx_failed),
+            ] {
+                self.telemetry_messages
+                    .with_label_values(&[outcome])
+                    .set(count as i64);
+            }
+            self.telemetry_spool_bytes.set(stats.spool_bytes as i64);
+            self.telemetry_connected.set(stats.connected as i64);
+        }
+
+        let mut counts: HashMap<(String, bool), i64> = HashMap::new();
+        for operation in operations.operation_store.lock().await.values() {
+            let kind = operation
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.type_url.rsplit('.').next())
+                .unwrap_or("unknown")
+                .to_string();
+            *counts.entry((kind, operation.done)).or_default() += 1;
+        }
+        self.operations.reset();
+        for ((kind, done), count) in counts {
+            self.operations
+                .with_label_values(&[&kind, if done { "true" } else { "false" }])
+                .set(count);
+        }
+    }
+
+    async fn render(&self, operations: &OperationsServiceImpl) -> Result<Vec<u8>> {
+        self.sample(operations).await;
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Serves `GET /metrics` on `addr` until the task is dropped.
+pub async fn serve_metrics(addr: SocketAddr, operations: Arc<OperationsServiceImpl>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let operations = operations.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let operations = operations.clone();
+                async move { Ok::<_, Infallible>(handle_request(request, &operations).await) }
+            }))
+        }
+    });
+
+    tracing::info!("Serving metrics on http://{}/metrics", addr);
+    hyper::Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
+
+async fn handle_request(
+    request: Request<Body>,
+    operations: &OperationsServiceImpl,
+) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match Metrics::global().render(operations).await {
+            Ok(buf) => {
+                response.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                *response.body_mut() = Body::from(buf);
+            }
+            Err(e) => {
+                tracing::error!("Failed to encode metrics: {}", e);
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        },
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    response
+}
+
+/// Tower layer that records a request counter and latency histogram for
+/// every gRPC method.
+///
+/// The status code is read from the `grpc-status` response header, which is
+/// where tonic puts errors returned before any message is sent. Successful
+/// calls carry their status in the trailers and are counted as `Ok`, and the
+/// latency of streaming calls is measured up to the response headers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics { inner }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // The clone may not be ready, so call the one that was polled.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let path = request.uri().path().to_string();
+        let start = Instant::now();
+
+        Box::pin(async move {
+            let result = inner.call(request).await;
+            let code = match &result {
+                Ok(response) => response
+                    .headers()
+                    .get("grpc-status")
+                    .map(|status| tonic::Code::from_bytes(status.as_bytes()))
+                    .unwrap_or(tonic::Code::Ok),
+                Err(_) => tonic::Code::Unavailable,
+            };
+            Metrics::global().observe_rpc(&path, code, start.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
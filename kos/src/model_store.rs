@@ -0,0 +1,448 @@
+//! On-disk registry of uploaded models.
+//!
+//! Models are keyed by the SHA-256 of their bytes, so uploading the same
+//! model twice yields the same UID. Each model is kept as `<uid>.model` with
+//! its metadata in `<uid>.json`; both survive restarts until the model is
+//! deleted. The store only deals with bytes, so any inference backend can
+//! use it.
+//!
+//! `ModelUploads` holds models that are still being received in chunks.
+
+use crate::config::kos_data_dir;
+use crate::hal::ModelMetadata;
+use crate::time_sync;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+const MODEL_EXTENSION: &str = "model";
+
+/// Largest model `ModelUploads` accepts unless configured otherwise.
+pub const DEFAULT_MAX_MODEL_SIZE: u64 = 1024 * 1024 * 1024;
+/// How long an abandoned partial upload is kept for resuming.
+pub const DEFAULT_UPLOAD_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const METADATA_EXTENSION: &str = "json";
+const PART_EXTENSION: &str = "part";
+
+/// On-disk form of `ModelMetadata`, plus what the store knows about the blob.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct StoredMetadata {
+    model_name: Option<String>,
+    model_description: Option<String>,
+    model_version: Option<String>,
+    model_author: Option<String>,
+    size_bytes: u64,
+    uploaded_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredModel {
+    pub uid: String,
+    pub metadata: Option<ModelMetadata>,
+    pub size_bytes: u64,
+    /// Upload time, in nanoseconds since the epoch.
+    pub uploaded_at: u64,
+}
+
+impl From<(String, StoredMetadata)> for StoredModel {
+    fn from((uid, stored): (String, StoredMetadata)) -> Self {
+        let has_metadata = stored.model_name.is_some()
+            || stored.model_description.is_some()
+            || stored.model_version.is_some()
+            || stored.model_author.is_some();
+        Self {
+            uid,
+            metadata: has_metadata.then_some(ModelMetadata {
+                model_name: stored.model_name,
+                model_description: stored.model_description,
+                model_version: stored.model_version,
+                model_author: stored.model_author,
+            }),
+            size_bytes: stored.size_bytes,
+            uploaded_at: stored.uploaded_at,
+        }
+    }
+}
+
+pub struct ModelStore {
+    dir: PathBuf,
+}
+
+impl ModelStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Opens the store in `<kos data dir>/models`.
+    pub fn open_default() -> Result<Self> {
+        Self::open(kos_data_dir().join("models"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The UID a model with these bytes is stored under.
+    pub fn model_uid(bytes: &[u8]) -> String {
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    /// UIDs end up in file names, so anything that isn't a hex digest is
+    /// treated as unknown rather than joined onto the store path.
+    pub fn is_valid_uid(uid: &str) -> bool {
+        uid.len() == 64 && uid.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    fn model_path(&self, uid: &str) -> PathBuf {
+        self.dir.join(uid).with_extension(MODEL_EXTENSION)
+    }
+
+    fn metadata_path(&self, uid: &str) -> PathBuf {
+        self.dir.join(uid).with_extension(METADATA_EXTENSION)
+    }
+
+    pub fn contains(&self, uid: &str) -> bool {
+        Self::is_valid_uid(uid) && self.model_path(uid).exists()
+    }
+
+    /// Stores `bytes` and returns the model UID along with whether the model
+    /// is new. Re-uploading a stored model keeps the blob and upload time;
+    /// its metadata is replaced only if new metadata is given.
+    pub async fn insert(
+        &self,
+        bytes: &[u8],
+        metadata: Option<ModelMetadata>,
+    ) -> Result<(String, bool)> {
+        let uid = Self::model_uid(bytes);
+        let existing = self.read_metadata(&uid).await?;
+        let is_new = existing.is_none() || !self.model_path(&uid).exists();
+
+        if is_new {
+            write_atomic(&self.model_path(&uid), bytes).await?;
+        } else if metadata.is_none() {
+            return Ok((uid, false));
+        }
+
+        let mut stored = existing.filter(|_| !is_new).unwrap_or(StoredMetadata {
+            size_bytes: bytes.len() as u64,
+            uploaded_at: time_sync::wall_ns(),
+            ..Default::default()
+        });
+        if let Some(metadata) = metadata {
+            stored.model_name = metadata.model_name;
+            stored.model_description = metadata.model_description;
+            stored.model_version = metadata.model_version;
+            stored.model_author = metadata.model_author;
+        }
+        write_atomic(
+            &self.metadata_path(&uid),
+            &serde_json::to_vec_pretty(&stored)?,
+        )
+        .await?;
+
+        Ok((uid, is_new))
+    }
+
+    pub async fn get(&self, uid: &str) -> Result<Option<StoredModel>> {
+        if !self.contains(uid) {
+            return Ok(None);
+        }
+        let stored = match self.read_metadata(uid).await? {
+            Some(stored) => stored,
+            None => StoredMetadata {
+                size_bytes: tokio::fs::metadata(self.model_path(uid)).await?.len(),
+                ..Default::default()
+            },
+        };
+        Ok(Some((uid.to_string(), stored).into()))
+    }
+
+    /// Reads the model bytes, or `None` if the model isn't stored.
+    pub async fn read(&self, uid: &str) -> Result<Option<Vec<u8>>> {
+        if !self.contains(uid) {
+            return Ok(None);
+        }
+        Ok(Some(tokio::fs::read(self.model_path(uid)).await?))
+    }
+
+    /// Lists stored models, oldest first.
+    pub async fn list(&self) -> Result<Vec<StoredModel>> {
+        let mut models = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(MODEL_EXTENSION) {
+                continue;
+            }
+            let Some(uid) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if !Self::is_valid_uid(uid) {
+                continue;
+            }
+            if let Some(model) = self.get(uid).await? {
+                models.push(model);
+            }
+        }
+        models.sort_by_key(|model| model.uploaded_at);
+        Ok(models)
+    }
+
+    /// Deletes a model. Returns whether it was stored.
+    pub async fn remove(&self, uid: &str) -> Result<bool> {
+        if !self.contains(uid) {
+            return Ok(false);
+        }
+        tokio::fs::remove_file(self.model_path(uid)).await?;
+        match tokio::fs::remove_file(self.metadata_path(uid)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(true)
+    }
+
+    async fn read_metadata(&self, uid: &str) -> Result<Option<StoredMetadata>> {
+        match tokio::fs::read(self.metadata_path(uid)).await {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(stored) => Ok(Some(stored)),
+                Err(e) => {
+                    tracing::warn!("Ignoring unreadable metadata for model {}: {}", uid, e);
+                    Ok(None)
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Writes through a temporary file so a crash never leaves a partial model
+/// under its final name.
+/// The temporary name is unique, so concurrent writes of the same file don't
+/// share one.
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", Uuid::new_v4()));
+    let tmp = PathBuf::from(tmp);
+
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    Ok(result?)
+}
+
+/// Models being uploaded in chunks, kept on disk as `<sha256>.part` so an
+/// interrupted upload can pick up where it left off. Partial uploads not
+/// written to for longer than the maximum age are removed when the uploads
+/// are opened and whenever an upload starts.
+pub struct ModelUploads {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    active: Mutex<HashSet<String>>,
+}
+
+impl ModelUploads {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let uploads = Self {
+            dir: dir.into(),
+            max_size: DEFAULT_MAX_MODEL_SIZE,
+            max_age: DEFAULT_UPLOAD_MAX_AGE,
+            active: Mutex::new(HashSet::new()),
+        };
+        remove_stale_parts(&uploads.dir, uploads.max_age, &HashSet::new());
+        uploads
+    }
+
+    /// Sets how long an abandoned partial upload is kept.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Caps the size of a single model, so an upload can't fill the disk.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    fn part_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(sha256).with_extension(PART_EXTENSION)
+    }
+
+    /// Bytes received so far for the model with this hash.
+    pub async fn received(&self, sha256: &str) -> Result<u64> {
+        match tokio::fs::metadata(self.part_path(sha256)).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Continues the upload of the model with this hash from `offset`,
+    /// dropping anything received past it. `offset` must not be past what
+    /// was received. Returns `None` if the same model is already being
+    /// uploaded.
+    pub async fn resume(&self, sha256: &str, offset: u64) -> Result<Option<PartialUpload<'_>>> {
+        if !ModelStore::is_valid_uid(sha256) {
+            eyre::bail!("Invalid SHA-256 {}", sha256);
+        }
+        let (inserted, others) = match self.active.lock() {
+            Ok(mut active) => {
+                let others = active.clone();
+                (active.insert(sha256.to_string()), others)
+            }
+            Err(e) => eyre::bail!("Upload registry is poisoned: {}", e),
+        };
+        if !inserted {
+            return Ok(None);
+        }
+        // From here on the guard releases the hash on every return path.
+        let mut upload = PartialUpload {
+            uploads: self,
+            sha256: sha256.to_string(),
+            file: None,
+            received: offset,
+        };
+
+        let (dir, max_age) = (self.dir.clone(), self.max_age);
+        let sha = sha256.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut skip = others;
+            skip.insert(sha);
+            remove_stale_parts(&dir, max_age, &skip)
+        })
+        .await?;
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.part_path(sha256))
+            .await?;
+        if file.metadata().await?.len() < offset {
+            eyre::bail!("Offset {} is past the received data", offset);
+        }
+        file.set_len(offset).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        upload.file = Some(file);
+        Ok(Some(upload))
+    }
+}
+
+/// Removes partial uploads last written more than `max_age` ago, except
+/// those for the hashes in `skip`. Failures are only logged.
+fn remove_stale_parts(dir: &Path, max_age: Duration, skip: &HashSet<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!("Failed to list partial uploads: {}", e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(PART_EXTENSION) {
+            continue;
+        }
+        if path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .is_some_and(|sha256| skip.contains(sha256))
+        {
+            continue;
+        }
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default() > max_age)
+            .unwrap_or(false);
+        if !stale {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => tracing::info!("Removed abandoned upload {}", path.display()),
+            Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// An upload in progress. Only one exists per model hash at a time.
+pub struct PartialUpload<'a> {
+    uploads: &'a ModelUploads,
+    sha256: String,
+    file: Option<tokio::fs::File>,
+    received: u64,
+}
+
+impl PartialUpload<'_> {
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Appends a chunk, refusing to grow past the maximum model size.
+    pub async fn append(&mut self, data: &[u8]) -> Result<()> {
+        let max_size = self.uploads.max_size;
+        if self.received + data.len() as u64 > max_size {
+            eyre::bail!("Upload is larger than the maximum of {} bytes", max_size);
+        }
+        let Some(file) = self.file.as_mut() else {
+            eyre::bail!("Upload {} is closed", self.sha256);
+        };
+        file.write_all(data).await?;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes what was received so far, so a later `resume` can continue
+    /// from `received()`.
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush().await?;
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Reads back the whole model and removes the partial file. The caller
+    /// verifies the result; a corrupt upload has to start over either way.
+    pub async fn finish(mut self) -> Result<Vec<u8>> {
+        self.flush().await?;
+        self.file = None;
+        let path = self.uploads.part_path(&self.sha256);
+        let data = tokio::fs::read(&path).await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(data)
+    }
+}
+
+impl Drop for PartialUpload<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.uploads.active.lock() {
+            active.remove(&self.sha256);
+        }
+    }
+}
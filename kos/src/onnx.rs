@@ -0,0 +1,616 @@
+//! Pure-CPU ONNX backend for the `Inference` HAL trait, built on `tract`.
+//!
+//! Works on any platform without an NPU. Models are kept in a `ModelStore`,
+//! so they survive restarts. Input and output specs are read from the model
+//! graph; dimensions the graph leaves symbolic (e.g. a batch size) are
+//! reported as dynamic. Inputs are cast to the model's own types at the
+//! boundary; FLOAT32 outputs are returned in `values` and other types in
+//! `raw_data`.
+
+use crate::hal::tensor::DataType;
+use crate::hal::{
+    tensor, ActionResponse, ForwardResponse, GetModelsInfoRequest, GetModelsInfoResponse,
+    Inference, LoadModelsResponse, ModelInfo, ModelMetadata, Tensor, UploadModelResponse,
+};
+use crate::kos_proto::common::{Error, ErrorCode};
+use crate::kos_proto::inference::get_models_info_request::Filter;
+use crate::model_store::{ModelStore, StoredModel};
+use async_trait::async_trait;
+use eyre::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tract_onnx::prelude::{
+    f16, DatumType, Framework, InferenceModelExt, OutletId, TValue, TVec, TractResult, TypedFact,
+    TypedModel, TypedRunnableModel,
+};
+use tract_onnx::tract_core::anyhow;
+
+type Plan = TypedRunnableModel<TypedModel>;
+
+/// Input and output specs read from a model graph.
+struct ModelSpecs {
+    inputs: HashMap<String, Tensor>,
+    outputs: HashMap<String, Tensor>,
+}
+
+struct LoadedModel {
+    plan: Arc<Plan>,
+    input_names: Vec<String>,
+    input_types: Vec<DatumType>,
+    output_names: Vec<String>,
+}
+
+pub struct OnnxInference {
+    store: ModelStore,
+    /// Specs of stored models, parsed on first use.
+    specs: RwLock<HashMap<String, Arc<ModelSpecs>>>,
+    loaded: RwLock<HashMap<String, LoadedModel>>,
+}
+
+impl OnnxInference {
+    pub fn new(store: ModelStore) -> Self {
+        Self {
+            store,
+            specs: RwLock::new(HashMap::new()),
+            loaded: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the specs of a stored model, parsing it if they aren't cached
+    /// yet. `None` if the model isn't stored.
+    async fn specs(&self, uid: &str) -> Result<Option<Arc<ModelSpecs>>> {
+        if let Some(specs) = self.specs.read().await.get(uid) {
+            return Ok(Some(specs.clone()));
+        }
+        let Some(bytes) = self.store.read(uid).await? else {
+            return Ok(None);
+        };
+        let specs = tokio::task::spawn_blocking(move || parse_specs(&bytes))
+            .await?
+            .map_err(tract_error)?;
+        let specs = Arc::new(specs);
+        self.specs
+            .write()
+            .await
+            .insert(uid.to_string(), specs.clone());
+        Ok(Some(specs))
+    }
+
+    async fn model_info(&self, model: StoredModel) -> Result<ModelInfo> {
+        let (input_specs, output_specs) = match self.specs(&model.uid).await {
+            Ok(Some(specs)) => (specs.inputs.clone(), specs.outputs.clone()),
+            Ok(None) => (HashMap::new(), HashMap::new()),
+            Err(e) => {
+                tracing::warn!("Failed to read specs of model {}: {}", model.uid, e);
+                (HashMap::new(), HashMap::new())
+            }
+        };
+        Ok(ModelInfo {
+            loaded: self.loaded.read().await.contains_key(&model.uid),
+            uid: model.uid,
+            metadata: model.metadata,
+            input_specs,
+            output_specs,
+            description: String::new(),
+            size_bytes: model.size_bytes,
+            uploaded_at: model.uploaded_at,
+        })
+    }
+}
+
+fn error(code: ErrorCode, message: impl Into<String>) -> Error {
+    Error {
+        code: code as i32,
+        message: message.into(),
+    }
+}
+
+/// Tract reports errors through `anyhow`, which doesn't convert into eyre.
+fn tract_error(e: anyhow::Error) -> eyre::Report {
+    eyre::eyre!("{:?}", e)
+}
+
+fn parse_model(bytes: &[u8]) -> TractResult<TypedModel> {
+    tract_onnx::onnx()
+        .model_for_read(&mut std::io::Cursor::new(bytes))?
+        .into_typed()
+}
+
+/// Graph inputs and outputs are named after their ONNX value names.
+fn outlet_names(model: &TypedModel, outlets: &[OutletId]) -> Vec<String> {
+    outlets
+        .iter()
+        .map(|outlet| {
+            model
+                .outlet_label(*outlet)
+                .unwrap_or(&model.node(outlet.node).name)
+                .to_string()
+        })
+        .collect()
+}
+
+/// The proto type for a tract type, if there is one.
+fn data_type(datum_type: DatumType) -> Option<DataType> {
+    Some(match datum_type {
+        DatumType::F32 => DataType::Float32,
+        DatumType::F16 => DataType::Float16,
+        DatumType::F64 => DataType::Float64,
+        DatumType::I8 => DataType::Int8,
+        DatumType::I16 => DataType::Int16,
+        DatumType::I32 => DataType::Int32,
+        DatumType::I64 => DataType::Int64,
+        DatumType::U8 => DataType::Uint8,
+        DatumType::U16 => DataType::Uint16,
+        DatumType::U32 => DataType::Uint32,
+        DatumType::U64 => DataType::Uint64,
+        DatumType::Bool => DataType::Bool,
+        _ => return None,
+    })
+}
+
+fn tensor_spec(fact: &TypedFact) -> Tensor {
+    Tensor {
+        values: vec![],
+        dtype: data_type(fact.datum_type).unwrap_or(DataType::Unspecified) as i32,
+        raw_data: vec![],
+        shape: fact
+            .shape
+            .iter()
+            .map(|dim| match dim.to_i64() {
+                Ok(size) => tensor::Dimension {
+                    size: size as u32,
+                    name: String::new(),
+                    dynamic: false,
+                },
+                Err(_) => tensor::Dimension {
+                    size: 0,
+                    name: dim.to_string(),
+                    dynamic: true,
+                },
+            })
+            .collect(),
+    }
+}
+
+fn model_specs(model: &TypedModel) -> TractResult<ModelSpecs> {
+    let specs = |outlets: &[OutletId]| -> TractResult<HashMap<String, Tensor>> {
+        outlet_names(model, outlets)
+            .into_iter()
+            .zip(outlets)
+            .map(|(name, outlet)| Ok((name, tensor_spec(model.outlet_fact(*outlet)?))))
+            .collect()
+    };
+
+    Ok(ModelSpecs {
+        inputs: specs(model.input_outlets()?)?,
+        outputs: specs(model.output_outlets()?)?,
+    })
+}
+
+fn parse_specs(bytes: &[u8]) -> TractResult<ModelSpecs> {
+    model_specs(&parse_model(bytes)?)
+}
+
+/// Optimizes the model for execution. Some graphs can't be fully optimized
+/// while dimensions are still symbolic, in which case the decluttered graph
+/// is run instead.
+fn build_plan(bytes: &[u8]) -> TractResult<LoadedModel> {
+    let model = parse_model(bytes)?;
+    let input_names = outlet_names(&model, model.input_outlets()?);
+    let output_names = outlet_names(&model, model.output_outlets()?);
+    let input_types = (0..input_names.len())
+        .map(|i| Ok(model.input_fact(i)?.datum_type))
+        .collect::<TractResult<Vec<_>>>()?;
+
+    let optimized = match model.clone().into_optimized() {
+        Ok(optimized) => optimized,
+        Err(e) => {
+            tracing::debug!("Running unoptimized model: {:?}", e);
+            model.into_decluttered()?
+        }
+    };
+
+    Ok(LoadedModel {
+        plan: Arc::new(optimized.into_runnable()?),
+        input_names,
+        input_types,
+        output_names,
+    })
+}
+
+/// Decodes little-endian `raw_data` into a tract tensor.
+fn decode_raw(
+    dtype: DataType,
+    shape: &[usize],
+    bytes: &[u8],
+) -> TractResult<tract_onnx::prelude::Tensor> {
+    macro_rules! decode {
+        ($t:ty) => {{
+            const SIZE: usize = std::mem::size_of::<$t>();
+            let values: Vec<$t> = bytes
+                .chunks_exact(SIZE)
+                .map(|chunk| {
+                    let mut buf = [0u8; SIZE];
+                    buf.copy_from_slice(chunk);
+                    <$t>::from_le_bytes(buf)
+                })
+                .collect();
+            tract_onnx::prelude::Tensor::from_shape(shape, &values)
+        }};
+    }
+
+    if dtype == DataType::Unspecified {
+        anyhow::bail!("Tensor dtype UNSPECIFIED can't carry data");
+    }
+    if bytes.len() % crate::tensor::dtype_size(dtype) != 0 {
+        anyhow::bail!("raw_data isn't a whole number of {:?} elements", dtype);
+    }
+    match dtype {
+        DataType::Float32 => decode!(f32),
+        DataType::Float16 => decode!(f16),
+        DataType::Float64 => decode!(f64),
+        DataType::Int8 => decode!(i8),
+        DataType::Int16 => decode!(i16),
+        DataType::Int32 => decode!(i32),
+        DataType::Int64 => decode!(i64),
+        DataType::Uint8 => decode!(u8),
+        DataType::Uint16 => decode!(u16),
+        DataType::Uint32 => decode!(u32),
+        DataType::Uint64 => decode!(u64),
+        DataType::Bool => {
+            let values: Vec<bool> = bytes.iter().map(|&b| b != 0).collect();
+            tract_onnx::prelude::Tensor::from_shape(shape, &values)
+        }
+        DataType::Unspecified => anyhow::bail!("Tensor dtype UNSPECIFIED can't carry data"),
+    }
+}
+
+/// Encodes a tract tensor of a proto-representable type as little-endian
+/// bytes.
+fn encode_raw(value: &tract_onnx::prelude::Tensor, dtype: DataType) -> TractResult<Vec<u8>> {
+    macro_rules! encode {
+        ($t:ty) => {
+            value
+                .as_slice::<$t>()?
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect()
+        };
+    }
+
+    Ok(match dtype {
+        DataType::Float32 => encode!(f32),
+        DataType::Float16 => encode!(f16),
+        DataType::Float64 => encode!(f64),
+        DataType::Int8 => encode!(i8),
+        DataType::Int16 => encode!(i16),
+        DataType::Int32 => encode!(i32),
+        DataType::Int64 => encode!(i64),
+        DataType::Uint8 => encode!(u8),
+        DataType::Uint16 => encode!(u16),
+        DataType::Uint32 => encode!(u32),
+        DataType::Uint64 => encode!(u64),
+        DataType::Bool => value.as_slice::<bool>()?.iter().map(|&b| b as u8).collect(),
+        DataType::Unspecified => anyhow::bail!("Tensor dtype UNSPECIFIED can't carry data"),
+    })
+}
+
+fn to_tract(tensor: &Tensor, datum_type: DatumType) -> TractResult<TValue> {
+    let dtype = crate::tensor::dtype(tensor).map_err(|e| anyhow::anyhow!(e))?;
+    let shape: Vec<usize> = if !tensor.shape.is_empty() {
+        tensor.shape.iter().map(|dim| dim.size as usize).collect()
+    } else if tensor.raw_data.is_empty() {
+        vec![tensor.values.len()]
+    } else {
+        vec![tensor.raw_data.len() / crate::tensor::dtype_size(dtype)]
+    };
+
+    let value = if tensor.raw_data.is_empty() {
+        tract_onnx::prelude::Tensor::from_shape(&shape, &tensor.values)?
+    } else {
+        decode_raw(dtype, &shape, &tensor.raw_data)?
+    };
+    Ok(value.cast_to_dt(datum_type)?.into_owned().into())
+}
+
+fn from_tract(value: &tract_onnx::prelude::Tensor) -> TractResult<Tensor> {
+    let shape = value
+        .shape()
+        .iter()
+        .map(|&size| tensor::Dimension {
+            size: size as u32,
+            name: String::new(),
+            dynamic: false,
+        })
+        .collect();
+
+    // FLOAT32 stays in `values` for existing clients. Types the proto can't
+    // express (e.g. symbolic dims) are cast to FLOAT32 as well.
+    match data_type(value.datum_type()) {
+        Some(dtype) if dtype != DataType::Float32 => Ok(Tensor {
+            values: vec![],
+            shape,
+            dtype: dtype as i32,
+            raw_data: encode_raw(value, dtype)?,
+        }),
+        _ => {
+            let value = value.cast_to::<f32>()?;
+            Ok(Tensor {
+                values: value.as_slice::<f32>()?.to_vec(),
+                shape,
+                dtype: DataType::Float32 as i32,
+                raw_data: vec![],
+            })
+        }
+    }
+}
+
+fn run_plan(
+    plan: &Plan,
+    input_names: &[String],
+    input_types: &[DatumType],
+    output_names: &[String],
+    inputs: &HashMap<String, Tensor>,
+) -> TractResult<HashMap<String, Tensor>> {
+    let inputs = input_names
+        .iter()
+        .zip(input_types)
+        .map(|(name, datum_type)| {
+            if data_type(*datum_type).is_none() {
+                anyhow::bail!(
+                    "Input {} takes {:?} elements, which can't be sent to the model",
+                    name,
+                    datum_type
+                );
+            }
+            let tensor = inputs
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Missing input tensor {}", name))?;
+            to_tract(tensor, *datum_type)
+        })
+        .collect::<TractResult<TVec<_>>>()?;
+
+    let outputs = plan.run(inputs)?;
+    output_names
+        .iter()
+        .zip(outputs.iter())
+        .map(|(name, value)| Ok((name.clone(), from_tract(value)?)))
+        .collect()
+}
+
+#[async_trait]
+impl Inference for OnnxInference {
+    async fn upload_model(
+        &self,
+        model: Vec<u8>,
+        metadata: Option<ModelMetadata>,
+    ) -> Result<UploadModelResponse> {
+        let uid = ModelStore::model_uid(&model);
+        let bytes = Arc::new(model);
+
+        // Only models tract can read are stored.
+        if !self.specs.read().await.contains_key(&uid) {
+            let parsed = {
+                let bytes = bytes.clone();
+                tokio::task::spawn_blocking(move || parse_specs(&bytes)).await?
+            };
+            match parsed {
+                Ok(specs) => {
+                    self.specs
+                        .write()
+                        .await
+                        .insert(uid.clone(), Arc::new(specs));
+                }
+                Err(e) => {
+                    return Ok(UploadModelResponse {
+                        model_uid: String::new(),
+                        error: Some(error(
+                            ErrorCode::InvalidArgument,
+                            format!("Failed to parse ONNX model: {:?}", e),
+                        )),
+                        ..Default::default()
+                    })
+                }
+            }
+        }
+
+        let (uid, is_new) = self.store.insert(&bytes, metadata).await?;
+        if is_new {
+            tracing::info!("Stored ONNX model {} ({} bytes)", uid, bytes.len());
+        } else {
+            tracing::info!("ONNX model {} is already stored", uid);
+        }
+
+        Ok(UploadModelResponse {
+            model_uid: uid,
+            error: None,
+            ..Default::default()
+        })
+    }
+
+    async fn get_models_info(
+        &self,
+        request: GetModelsInfoRequest,
+    ) -> Result<GetModelsInfoResponse> {
+        let models = match request.filter {
+            Some(Filter::ModelUids(uids)) => {
+                let mut models = vec![];
+                let mut missing = vec![];
+                for uid in uids.uids {
+                    match self.store.get(&uid).await? {
+                        Some(model) => models.push(model),
+                        None => missing.push(uid),
+                    }
+                }
+                if !missing.is_empty() {
+                    return Ok(GetModelsInfoResponse {
+                        models: vec![],
+                        error: Some(error(
+                            ErrorCode::InvalidArgument,
+                            format!("Unknown model UIDs: {:?}", missing),
+                        )),
+                    });
+                }
+                models
+            }
+            Some(Filter::All(_)) | None => self.store.list().await?,
+        };
+
+        let mut infos = Vec::with_capacity(models.len());
+        for model in models {
+            infos.push(self.model_info(model).await?);
+        }
+
+        Ok(GetModelsInfoResponse {
+            models: infos,
+            error: None,
+        })
+    }
+
+    async fn load_models(&self, uids: Vec<String>) -> Result<LoadModelsResponse> {
+        let mut infos = vec![];
+        for uid in uids {
+            let model = match self.store.get(&uid).await? {
+                Some(model) => model,
+                None => {
+                    return Ok(LoadModelsResponse {
+                        models: infos,
+                        result: Some(ActionResponse {
+                            success: false,
+                            error: Some(error(
+                                ErrorCode::InvalidArgument,
+                                format!("Unknown model UID {}", uid),
+                            )),
+                        }),
+                    })
+                }
+            };
+
+            if !self.loaded.read().await.contains_key(&uid) {
+                let Some(bytes) = self.store.read(&uid).await? else {
+                    eyre::bail!("Model {} was deleted while loading", uid);
+                };
+                let loaded = tokio::task::spawn_blocking(move || build_plan(&bytes)).await?;
+                match loaded {
+                    Ok(loaded) => {
+                        self.loaded.write().await.insert(uid.clone(), loaded);
+                        tracing::info!("Loaded ONNX model {}", uid);
+                    }
+                    Err(e) => {
+                        return Ok(LoadModelsResponse {
+                            models: infos,
+                            result: Some(ActionResponse {
+                                success: false,
+                                error: Some(error(
+                                    ErrorCode::InvalidArgument,
+                                    format!("Failed to load model {}: {:?}", uid, e),
+                                )),
+                            }),
+                        })
+                    }
+                }
+            }
+            infos.push(self.model_info(model).await?);
+        }
+
+        Ok(LoadModelsResponse {
+            models: infos,
+            result: Some(ActionResponse {
+                success: true,
+                error: None,
+            }),
+        })
+    }
+
+    async fn unload_models(&self, uids: Vec<String>) -> Result<ActionResponse> {
+        // Check every UID first so a bad one leaves all models loaded.
+        let mut loaded = self.loaded.write().await;
+        let missing: Vec<_> = uids
+            .iter()
+            .filter(|uid| !loaded.contains_key(*uid))
+            .collect();
+
+        if !missing.is_empty() {
+            return Ok(ActionResponse {
+                success: false,
+                error: Some(error(
+                    ErrorCode::InvalidArgument,
+                    format!("Models are not loaded: {:?}", missing),
+                )),
+            });
+        }
+        for uid in &uids {
+            loaded.remove(uid);
+        }
+        Ok(ActionResponse {
+            success: true,
+            error: None,
+        })
+    }
+
+    async fn delete_model(&self, uid: String) -> Result<ActionResponse> {
+        let unloaded = self.loaded.write().await.remove(&uid).is_some();
+        self.specs.write().await.remove(&uid);
+        if !self.store.remove(&uid).await? {
+            return Ok(ActionResponse {
+                success: false,
+                error: Some(error(
+                    ErrorCode::InvalidArgument,
+                    format!("Unknown model UID {}", uid),
+                )),
+            });
+        }
+
+        tracing::info!(
+            "Deleted ONNX model {}{}",
+            uid,
+            if unloaded { " (unloaded first)" } else { "" }
+        );
+        Ok(ActionResponse {
+            success: true,
+            error: None,
+        })
+    }
+
+    async fn forward(
+        &self,
+        model_uid: String,
+        inputs: HashMap<String, Tensor>,
+    ) -> Result<ForwardResponse> {
+        let (plan, input_names, input_types, output_names) =
+            match self.loaded.read().await.get(&model_uid) {
+                Some(model) => (
+                    model.plan.clone(),
+                    model.input_names.clone(),
+                    model.input_types.clone(),
+                    model.output_names.clone(),
+                ),
+                None => {
+                    return Ok(ForwardResponse {
+                        outputs: HashMap::new(),
+                        error: Some(error(
+                            ErrorCode::InvalidArgument,
+                            format!("Model {} is not loaded", model_uid),
+                        )),
+                    })
+                }
+            };
+
+        let result = tokio::task::spawn_blocking(move || {
+            run_plan(&plan, &input_names, &input_types, &output_names, &inputs)
+        })
+        .await?;
+
+        Ok(match result {
+            Ok(outputs) => ForwardResponse {
+                outputs,
+                error: None,
+            },
+            Err(e) => ForwardResponse {
+                outputs: HashMap::new(),
+                error: Some(error(ErrorCode::InvalidArgument, format!("{:?}", e))),
+            },
+        })
+    }
+}
@@ -0,0 +1,531 @@
+//! Per-model policy config: how the observation vector is assembled from
+//! the robot's sensors and how the action vector is turned into actuator
+//! commands.
+//!
+//! ```json
+//! {
+//!   "rate_hz": 50,
+//!   "command_timeout_ms": 500,
+//!   "angle_units": "radians",
+//!   "observation_input": "obs",
+//!   "action_output": "actions",
+//!   "joints": [
+//!     { "name": "left_knee", "actuator_id": 14, "default_position": 0.6, "min_position": -0.1, "max_position": 2.0 },
+//!     { "name": "right_knee", "actuator_id": 24, "default_position": -0.6 }
+//!   ],
+//!   "observation": [
+//!     { "type": "projected_gravity" },
+//!     { "type": "gyro", "scale": 0.25 },
+//!     { "type": "command", "names": ["vx", "vy", "yaw_rate"], "scale": [2.0, 2.0, 0.25] },
+//!     { "type": "joint_position" },
+//!     { "type": "joint_velocity", "scale": 0.05 },
+//!     { "type": "last_action" }
+//!   ],
+//!   "action": { "scale": 0.25, "clip": 100.0 }
+//! }
+//! ```
+//!
+//! Each observation component is normalized as `(value - offset) * scale`,
+//! then clipped to `±clip`. `offset` and `scale` are a number or one value
+//! per element. Joint positions default to an offset of the joint's
+//! `default_position`. Actions map to joints in `action.joints` order (all
+//! joints by default): each raw action is clipped to `±action.clip`, then
+//! the joint target is `default_position + action * action.scale *
+//! action_scale`, limited to the joint's min/max. `angle_units` sets the
+//! units of joint positions, velocities, the gyro and every angle in the
+//! config; actuators and the IMU always talk in degrees. When a run ends,
+//! the driven joints hold their current position, or have their torque
+//! disabled if `action.on_stop` is `"disable_torque"`.
+//!
+//! `command` components read the latest `SetPolicyCommand` values. If none
+//! arrive for `command_timeout_ms`, e.g. because the joystick client went
+//! away, the commands drop back to zero.
+
+use crate::hal::{ActuatorCommand, ActuatorStateResponse, ImuValuesResponse, QuaternionResponse};
+use eyre::Result;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+fn default_rate_hz() -> f64 {
+    50.0
+}
+
+fn default_command_timeout_ms() -> u64 {
+    500
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AngleUnits {
+    #[default]
+    Degrees,
+    Radians,
+}
+
+impl AngleUnits {
+    /// Converts an angle (or angular velocity) in degrees to these units.
+    fn degrees_to_units(self, value: f64) -> f64 {
+        match self {
+            AngleUnits::Degrees => value,
+            AngleUnits::Radians => value.to_radians(),
+        }
+    }
+
+    fn units_to_degrees(self, value: f64) -> f64 {
+        match self {
+            AngleUnits::Degrees => value,
+            AngleUnits::Radians => value.to_degrees(),
+        }
+    }
+}
+
+/// A number applied to every element, or one value per element.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Values {
+    All(f32),
+    Each(Vec<f32>),
+}
+
+impl Values {
+    fn get(&self, index: usize) -> f32 {
+        match self {
+            Values::All(value) => *value,
+            Values::Each(values) => values[index],
+        }
+    }
+
+    fn check_len(&self, len: usize, what: &str) -> Result<()> {
+        match self {
+            Values::Each(values) if values.len() != len => {
+                eyre::bail!("{} has {} values for {} elements", what, values.len(), len)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JointConfig {
+    pub name: String,
+    pub actuator_id: u32,
+    /// Pose the joint holds for a zero action.
+    #[serde(default)]
+    pub default_position: f32,
+    #[serde(default)]
+    pub min_position: Option<f32>,
+    #[serde(default)]
+    pub max_position: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObservationSource {
+    /// Positions of `joints` (all joints if empty).
+    JointPosition {
+        #[serde(default)]
+        joints: Vec<String>,
+    },
+    /// Velocities of `joints` (all joints if empty).
+    JointVelocity {
+        #[serde(default)]
+        joints: Vec<String>,
+    },
+    /// Unit gravity vector in the IMU frame, from the IMU quaternion.
+    ProjectedGravity,
+    /// IMU angular velocity (x, y, z).
+    Gyro,
+    /// IMU acceleration (x, y, z) in m/s^2.
+    Accel,
+    /// The previous step's clipped raw actions, zero on the first step.
+    LastAction,
+    /// Named user commands, e.g. velocity targets, from `SetPolicyCommand`.
+    /// Zero until set and once they go stale.
+    Command { names: Vec<String> },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ObservationComponent {
+    #[serde(flatten)]
+    pub source: ObservationSource,
+    #[serde(default)]
+    pub offset: Option<Values>,
+    #[serde(default)]
+    pub scale: Option<Values>,
+    #[serde(default)]
+    pub clip: Option<f32>,
+}
+
+/// What the driven joints do once a run ends.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopBehavior {
+    /// Command each joint to stay where it is.
+    #[default]
+    Hold,
+    /// Turn torque off, letting the joints go limp.
+    DisableTorque,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ActionConfig {
+    /// Joints the action vector drives, in order (all joints if empty).
+    #[serde(default)]
+    pub joints: Vec<String>,
+    #[serde(default)]
+    pub scale: Option<Values>,
+    #[serde(default)]
+    pub clip: Option<f32>,
+    #[serde(default)]
+    pub on_stop: StopBehavior,
+}
+
+/// What a step needs from the sensors. Only what the observation uses is
+/// read.
+#[derive(Default)]
+pub struct SensorReadings {
+    pub imu: Option<ImuValuesResponse>,
+    pub quaternion: Option<QuaternionResponse>,
+    pub actuators: HashMap<u32, ActuatorStateResponse>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolicyConfig {
+    /// Control loop rate.
+    #[serde(default = "default_rate_hz")]
+    pub rate_hz: f64,
+    /// Commands not refreshed within this long read as zero again; 0 keeps
+    /// them until replaced.
+    #[serde(default = "default_command_timeout_ms")]
+    pub command_timeout_ms: u64,
+    #[serde(default)]
+    pub angle_units: AngleUnits,
+    /// Model input the observation vector is passed as.
+    pub observation_input: String,
+    /// Model output holding the action vector.
+    pub action_output: String,
+    pub joints: Vec<JointConfig>,
+    pub observation: Vec<ObservationComponent>,
+    pub action: ActionConfig,
+}
+
+impl PolicyConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|e| eyre::eyre!("Failed to read policy config {}: {}", path.display(), e))?;
+        let config: PolicyConfig = serde_json::from_slice(&data)
+            .map_err(|e| eyre::eyre!("Invalid policy config {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| eyre::eyre!("Invalid policy config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.rate_hz.is_finite() || self.rate_hz <= 0.0 {
+            eyre::bail!("rate_hz must be positive");
+        }
+        let mut names = HashSet::new();
+        let mut ids = HashMap::new();
+        for joint in &self.joints {
+            if !names.insert(joint.name.as_str()) {
+                eyre::bail!("Joint '{}' is listed twice", joint.name);
+            }
+            if let Some(other) = ids.insert(joint.actuator_id, joint.name.as_str()) {
+                eyre::bail!(
+                    "Joints '{}' and '{}' share actuator {}",
+                    other,
+                    joint.name,
+                    joint.actuator_id
+                );
+            }
+        }
+
+        for (i, component) in self.observation.iter().enumerate() {
+            let what = format!("Observation component {}", i);
+            if let ObservationSource::JointPosition { joints }
+            | ObservationSource::JointVelocity { joints } = &component.source
+            {
+                self.joints_by_name(joints)?;
+            }
+            let len = self.component_len(component);
+            if let Some(offset) = &component.offset {
+                offset.check_len(len, &format!("{} offset", what))?;
+            }
+            if let Some(scale) = &component.scale {
+                scale.check_len(len, &format!("{} scale", what))?;
+            }
+        }
+
+        let action_len = self.action_joints()?.len();
+        if action_len == 0 {
+            eyre::bail!("The action drives no joints");
+        }
+        if let Some(scale) = &self.action.scale {
+            scale.check_len(action_len, "Action scale")?;
+        }
+        Ok(())
+    }
+
+    /// Looks up joints by name, or returns all joints if `names` is empty.
+    fn joints_by_name(&self, names: &[String]) -> Result<Vec<&JointConfig>> {
+        if names.is_empty() {
+            return Ok(self.joints.iter().collect());
+        }
+        names
+            .iter()
+            .map(|name| {
+                self.joints
+                    .iter()
+                    .find(|joint| &joint.name == name)
+                    .ok_or_else(|| eyre::eyre!("Unknown joint '{}'", name))
+            })
+            .collect()
+    }
+
+    fn action_joints(&self) -> Result<Vec<&JointConfig>> {
+        self.joints_by_name(&self.action.joints)
+    }
+
+    fn component_len(&self, component: &ObservationComponent) -> usize {
+        match &component.source {
+            ObservationSource::JointPosition { joints }
+            | ObservationSource::JointVelocity { joints } => {
+                if joints.is_empty() {
+                    self.joints.len()
+                } else {
+                    joints.len()
+                }
+            }
+            ObservationSource::ProjectedGravity
+            | ObservationSource::Gyro
+            | ObservationSource::Accel => 3,
+            ObservationSource::LastAction => self.action_len(),
+            ObservationSource::Command { names } => names.len(),
+        }
+    }
+
+    pub fn observation_len(&self) -> usize {
+        self.observation
+            .iter()
+            .map(|component| self.component_len(component))
+            .sum()
+    }
+
+    pub fn action_len(&self) -> usize {
+        if self.action.joints.is_empty() {
+            self.joints.len()
+        } else {
+            self.action.joints.len()
+        }
+    }
+
+    /// Actuators the action vector drives, in order.
+    pub fn commanded_actuators(&self) -> Vec<u32> {
+        self.action_joints()
+            .unwrap_or_default()
+            .iter()
+            .map(|joint| joint.actuator_id)
+            .collect()
+    }
+
+    /// Actuators whose state the observation reads.
+    pub fn observed_actuators(&self) -> Vec<u32> {
+        let mut ids = vec![];
+        for component in &self.observation {
+            if let ObservationSource::JointPosition { joints }
+            | ObservationSource::JointVelocity { joints } = &component.source
+            {
+                for joint in self.joints_by_name(joints).unwrap_or_default() {
+                    if !ids.contains(&joint.actuator_id) {
+                        ids.push(joint.actuator_id);
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    /// Names of the commands the observation reads.
+    pub fn command_names(&self) -> HashSet<String> {
+        self.observation
+            .iter()
+            .flat_map(|component| match &component.source {
+                ObservationSource::Command { names } => names.clone(),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    pub fn uses_imu_values(&self) -> bool {
+        self.observation.iter().any(|component| {
+            matches!(
+                component.source,
+                ObservationSource::Gyro | ObservationSource::Accel
+            )
+        })
+    }
+
+    pub fn uses_quaternion(&self) -> bool {
+        self.observation
+            .iter()
+            .any(|component| matches!(component.source, ObservationSource::ProjectedGravity))
+    }
+
+    /// Assembles the observation vector.
+    pub fn observation(
+        &self,
+        sensors: &SensorReadings,
+        last_action: &[f32],
+        commands: &HashMap<String, f32>,
+    ) -> Result<Vec<f32>> {
+        let units = self.angle_units;
+        let mut observation = Vec::with_capacity(self.observation_len());
+        for component in &self.observation {
+            let (values, default_offset): (Vec<f64>, Option<Vec<f64>>) = match &component.source {
+                ObservationSource::JointPosition { joints } => {
+                    let joints = self.joints_by_name(joints)?;
+                    let mut positions = Vec::with_capacity(joints.len());
+                    for joint in &joints {
+                        let position =
+                            actuator_state(sensors, joint)?.position.ok_or_else(|| {
+                                eyre::eyre!(
+                                    "Actuator {} did not report position",
+                                    joint.actuator_id
+                                )
+                            })?;
+                        positions.push(units.degrees_to_units(position));
+                    }
+                    let defaults = joints
+                        .iter()
+                        .map(|joint| joint.default_position as f64)
+                        .collect();
+                    (positions, Some(defaults))
+                }
+                ObservationSource::JointVelocity { joints } => {
+                    let mut velocities = vec![];
+                    for joint in self.joints_by_name(joints)? {
+                        let velocity =
+                            actuator_state(sensors, joint)?.velocity.ok_or_else(|| {
+                                eyre::eyre!(
+                                    "Actuator {} did not report velocity",
+                                    joint.actuator_id
+                                )
+                            })?;
+                        velocities.push(units.degrees_to_units(velocity));
+                    }
+                    (velocities, None)
+                }
+                ObservationSource::ProjectedGravity => {
+                    let q = sensors
+                        .quaternion
+                        .as_ref()
+                        .ok_or_else(|| eyre::eyre!("No IMU orientation"))?;
+                    (projected_gravity(q).to_vec(), None)
+                }
+                ObservationSource::Gyro => {
+                    let imu = sensors
+                        .imu
+                        .as_ref()
+                        .ok_or_else(|| eyre::eyre!("No IMU values"))?;
+                    let gyro = [imu.gyro_x, imu.gyro_y, imu.gyro_z];
+                    (gyro.map(|v| units.degrees_to_units(v)).to_vec(), None)
+                }
+                ObservationSource::Accel => {
+                    let imu = sensors
+                        .imu
+                        .as_ref()
+                        .ok_or_else(|| eyre::eyre!("No IMU values"))?;
+                    (vec![imu.accel_x, imu.accel_y, imu.accel_z], None)
+                }
+                ObservationSource::LastAction => {
+                    (last_action.iter().map(|&v| v as f64).collect(), None)
+                }
+                ObservationSource::Command { names } => (
+                    names
+                        .iter()
+                        .map(|name| commands.get(name).copied().unwrap_or_default() as f64)
+                        .collect(),
+                    None,
+                ),
+            };
+
+            for (i, value) in values.into_iter().enumerate() {
+                let offset = match (&component.offset, &default_offset) {
+                    (Some(offset), _) => offset.get(i) as f64,
+                    (None, Some(defaults)) => defaults[i],
+                    (None, None) => 0.0,
+                };
+                let scale = component.scale.as_ref().map_or(1.0, |scale| scale.get(i)) as f64;
+                let mut value = ((value - offset) * scale) as f32;
+                if let Some(clip) = component.clip {
+                    value = value.clamp(-clip, clip);
+                }
+                observation.push(value);
+            }
+        }
+        Ok(observation)
+    }
+
+    /// Clips the raw actions in place and turns them into position commands.
+    pub fn commands(&self, actions: &mut [f32], action_scale: f32) -> Result<Vec<ActuatorCommand>> {
+        let joints = self.action_joints()?;
+        if actions.len() != joints.len() {
+            eyre::bail!(
+                "Model returned {} actions for {} joints",
+                actions.len(),
+                joints.len()
+            );
+        }
+
+        Ok(joints
+            .iter()
+            .zip(actions.iter_mut())
+            .enumerate()
+            .map(|(i, (joint, action))| {
+                if let Some(clip) = self.action.clip {
+                    *action = action.clamp(-clip, clip);
+                }
+                let scale = self.action.scale.as_ref().map_or(1.0, |scale| scale.get(i));
+                let mut target = joint.default_position + *action * scale * action_scale;
+                if let Some(min) = joint.min_position {
+                    target = target.max(min);
+                }
+                if let Some(max) = joint.max_position {
+                    target = target.min(max);
+                }
+                ActuatorCommand {
+                    actuator_id: joint.actuator_id,
+                    position: Some(self.angle_units.units_to_degrees(target as f64)),
+                    velocity: None,
+                    torque: None,
+                }
+            })
+            .collect())
+    }
+}
+
+fn actuator_state<'a>(
+    sensors: &'a SensorReadings,
+    joint: &JointConfig,
+) -> Result<&'a ActuatorStateResponse> {
+    sensors.actuators.get(&joint.actuator_id).ok_or_else(|| {
+        eyre::eyre!(
+            "No state for actuator {} ({})",
+            joint.actuator_id,
+            joint.name
+        )
+    })
+}
+
+/// World gravity (0, 0, -1) rotated into the IMU frame.
+fn projected_gravity(q: &QuaternionResponse) -> [f64; 3] {
+    let (w, x, y, z) = (q.w, q.x, q.y, q.z);
+    [
+        -2.0 * (x * z - w * y),
+        -2.0 * (y * z + w * x),
+        -(1.0 - 2.0 * (x * x + y * y)),
+    ]
+}
@@ -0,0 +1,670 @@
+//! Built-in `Policy` that runs a loaded model in closed loop on the robot.
+//!
+//! `StartPolicy`'s `action` is the UID of a loaded model. Its config is read
+//! from `<dir>/<model_uid>.json` on every start, so edits apply to the next
+//! run; see `policy_config` for the format. Each step, at a fixed rate, the
+//! runner reads the sensors the config observes, builds the observation
+//! tensor, runs `Inference::forward`, decodes the actions (with
+//! `action_scale` on top of the config's scale) and sends them to the
+//! actuators as position targets (only logged in `dry_run`). A run ends after `episode_length`
+//! steps (never if it's 0 or less), on `StopPolicy`, or on the first step
+//! that fails. However it ends, the driven joints are then told to hold
+//! their position (or have their torque disabled, per the config) so they
+//! aren't left chasing the model's last target.
+//!
+//! `SetPolicyCommand` steers a running policy: its values feed the
+//! observation's `command` components from the next step on, until they
+//! are replaced or go stale.
+
+use crate::config::kos_data_dir;
+use crate::hal::tensor::Dimension;
+use crate::hal::{
+    get_models_info_request, ActionResponse, ActionResult, Actuator, ActuatorCommand,
+    ConfigureActuatorRequest, GetModelsInfoRequest, GetStateResponse, Inference, ModelUids, Policy,
+    StartPolicyResponse, StopPolicyResponse, Tensor, IMU,
+};
+use crate::kos_proto::common::{Error, ErrorCode};
+use crate::kos_proto::telemetry::PolicyEvent;
+use crate::policy_config::{PolicyConfig, SensorReadings, StopBehavior};
+use crate::telemetry::Telemetry;
+use crate::tensor;
+use async_trait::async_trait;
+use eyre::Result;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
+
+/// Jitter percentiles are taken over this many recent steps.
+const JITTER_WINDOW: usize = 1000;
+
+/// Loop timing, kept after a run ends so it can still be inspected.
+#[derive(Default)]
+struct RunStats {
+    policy_uuid: String,
+    model_uid: String,
+    action_scale: f32,
+    episode_length: i32,
+    dry_run: bool,
+    rate_hz: f64,
+    running: bool,
+    steps: u64,
+    /// How late each step started relative to its schedule, in ms.
+    jitter_ms: VecDeque<f64>,
+    jitter_max_ms: f64,
+    step_time_total_ms: f64,
+    step_time_max_ms: f64,
+    /// Steps whose work took longer than the loop period.
+    overruns: u64,
+    /// Whether the command values timed out and read as zero.
+    commands_stale: bool,
+    last_error: Option<String>,
+}
+
+impl RunStats {
+    fn record(&mut self, jitter_ms: f64, step_time_ms: f64, period_ms: f64) {
+        self.steps += 1;
+        if self.jitter_ms.len() == JITTER_WINDOW {
+            self.jitter_ms.pop_front();
+        }
+        self.jitter_ms.push_back(jitter_ms);
+        self.jitter_max_ms = self.jitter_max_ms.max(jitter_ms);
+        self.step_time_total_ms += step_time_ms;
+        self.step_time_max_ms = self.step_time_max_ms.max(step_time_ms);
+        if step_time_ms > period_ms {
+            self.overruns += 1;
+        }
+    }
+
+    fn to_state(&self) -> HashMap<String, String> {
+        let mut state = HashMap::new();
+        if self.policy_uuid.is_empty() {
+            return state;
+        }
+
+        let mut jitter: Vec<f64> = self.jitter_ms.iter().copied().collect();
+        jitter.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f64| {
+            let rank = (p * jitter.len() as f64).ceil() as usize;
+            jitter
+                .get(rank.clamp(1, jitter.len().max(1)) - 1)
+                .copied()
+                .unwrap_or_default()
+        };
+        let mean = |total: f64, count: usize| {
+            if count == 0 {
+                0.0
+            } else {
+                total / count as f64
+            }
+        };
+
+        let mut insert = |key: &str, value: String| {
+            state.insert(key.to_string(), value);
+        };
+        insert("policy_uuid", self.policy_uuid.clone());
+        insert("action", self.model_uid.clone());
+        insert("action_scale", self.action_scale.to_string());
+        insert("episode_length", self.episode_length.to_string());
+        insert("dry_run", self.dry_run.to_string());
+        insert("running", self.running.to_string());
+        insert("rate_hz", self.rate_hz.to_string());
+        insert("steps", self.steps.to_string());
+        insert(
+            "jitter_mean_ms",
+            format!("{:.3}", mean(jitter.iter().sum(), jitter.len())),
+        );
+        insert("jitter_p50_ms", format!("{:.3}", percentile(0.5)));
+        insert("jitter_p99_ms", format!("{:.3}", percentile(0.99)));
+        insert("jitter_max_ms", format!("{:.3}", self.jitter_max_ms));
+        insert(
+            "step_time_mean_ms",
+            format!("{:.3}", mean(self.step_time_total_ms, self.steps as usize)),
+        );
+        insert("step_time_max_ms", format!("{:.3}", self.step_time_max_ms));
+        insert("overruns", self.overruns.to_string());
+        insert("commands_stale", self.commands_stale.to_string());
+        if let Some(error) = &self.last_error {
+            insert("last_error", error.clone());
+        }
+        state
+    }
+}
+
+/// The latest `SetPolicyCommand` values.
+#[derive(Default)]
+struct Commands {
+    values: HashMap<String, f32>,
+    received_at: Option<Instant>,
+}
+
+struct ActiveRun {
+    policy_uuid: String,
+    /// Commands the running config reads.
+    command_names: HashSet<String>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+pub struct PolicyRunner {
+    inference: Arc<dyn Inference>,
+    imu: Arc<dyn IMU>,
+    actuator: Arc<dyn Actuator>,
+    config_dir: PathBuf,
+    run: Mutex<Option<ActiveRun>>,
+    stats: Arc<Mutex<RunStats>>,
+    commands: Arc<Mutex<Commands>>,
+}
+
+impl PolicyRunner {
+    /// Reads policy configs from `<kos data dir>/policies`.
+    pub fn new(
+        inference: Arc<dyn Inference>,
+        imu: Arc<dyn IMU>,
+        actuator: Arc<dyn Actuator>,
+    ) -> Self {
+        Self {
+            inference,
+            imu,
+            actuator,
+            config_dir: kos_data_dir().join("policies"),
+            run: Mutex::new(None),
+            stats: Arc::new(Mutex::new(RunStats::default())),
+            commands: Arc::new(Mutex::new(Commands::default())),
+        }
+    }
+
+    pub fn with_config_dir(mut self, config_dir: impl Into<PathBuf>) -> Self {
+        self.config_dir = config_dir.into();
+        self
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    fn load_config(&self, model_uid: &str) -> Result<PolicyConfig> {
+        if model_uid.is_empty() || model_uid.contains(['/', '\\', '.']) {
+            eyre::bail!("Invalid model UID {:?}", model_uid);
+        }
+        PolicyConfig::load(self.config_dir.join(model_uid).with_extension("json"))
+    }
+
+    /// Checks that the model is known and that its observation input can
+    /// take the configured observation vector, returning the shape to send.
+    async fn observation_shape(
+        &self,
+        model_uid: &str,
+        config: &PolicyConfig,
+    ) -> Result<Vec<Dimension>> {
+        let response = self
+            .inference
+            .get_models_info(GetModelsInfoRequest {
+                filter: Some(get_models_info_request::Filter::ModelUids(ModelUids {
+                    uids: vec![model_uid.to_string()],
+                })),
+            })
+            .await?;
+        if let Some(error) = response.error {
+            eyre::bail!(error.message);
+        }
+        let Some(info) = response
+            .models
+            .into_iter()
+            .find(|info| info.uid == model_uid)
+        else {
+            eyre::bail!("Unknown model UID {}", model_uid);
+        };
+
+        let len = config.observation_len() as u32;
+        let dim = |size| Dimension {
+            size,
+            name: String::new(),
+            dynamic: false,
+        };
+        // Backends that don't report specs get a flat vector.
+        let Some(spec) = info.input_specs.get(&config.observation_input) else {
+            if info.input_specs.is_empty() {
+                return Ok(vec![dim(len)]);
+            }
+            eyre::bail!("Model has no input '{}'", config.observation_input);
+        };
+
+        // A leading dim other than the last is taken as a batch of 1.
+        let shape: Vec<Dimension> = match spec.shape.len() {
+            1 => vec![dim(len)],
+            2 => vec![dim(1), dim(len)],
+            rank => eyre::bail!(
+                "Input '{}' has rank {}, expected 1 or 2",
+                config.observation_input,
+                rank
+            ),
+        };
+        if let Some(last) = spec.shape.last() {
+            if !last.dynamic && last.size != len {
+                eyre::bail!(
+                    "Input '{}' takes {} values, the config observes {}",
+                    config.observation_input,
+                    last.size,
+                    len
+                );
+            }
+        }
+        Ok(shape)
+    }
+}
+
+/// Everything a run needs, moved into the control loop task.
+struct RunLoop {
+    inference: Arc<dyn Inference>,
+    imu: Arc<dyn IMU>,
+    actuator: Arc<dyn Actuator>,
+    stats: Arc<Mutex<RunStats>>,
+    commands: Arc<Mutex<Commands>>,
+    config: PolicyConfig,
+    model_uid: String,
+    observation_shape: Vec<Dimension>,
+    observed_actuators: Vec<u32>,
+    /// Clipped raw actions of the previous step.
+    last_action: Vec<f32>,
+    action_scale: f32,
+    episode_length: i32,
+    dry_run: bool,
+}
+
+impl RunLoop {
+    async fn read_sensors(&self) -> Result<SensorReadings> {
+        let config = &self.config;
+        let (imu, quaternion, states) = tokio::join!(
+            async {
+                if config.uses_imu_values() {
+                    self.imu.get_values().await.map(Some)
+                } else {
+                    Ok(None)
+                }
+            },
+            async {
+                if config.uses_quaternion() {
+                    self.imu.get_quaternion().await.map(Some)
+                } else {
+                    Ok(None)
+                }
+            },
+            async {
+                if self.observed_actuators.is_empty() {
+                    Ok(vec![])
+                } else {
+                    self.actuator
+                        .get_actuators_state(self.observed_actuators.clone())
+                        .await
+                }
+            }
+        );
+        Ok(SensorReadings {
+            imu: imu?,
+            quaternion: quaternion?,
+            actuators: states?
+                .into_iter()
+                .map(|state| (state.actuator_id, state))
+                .collect(),
+        })
+    }
+
+    /// The latest commands, or none once they're older than the timeout.
+    async fn commands(&self) -> HashMap<String, f32> {
+        let commands = self.commands.lock().await;
+        let timeout = Duration::from_millis(self.config.command_timeout_ms);
+        let stale = match commands.received_at {
+            Some(received_at) => !timeout.is_zero() && received_at.elapsed() > timeout,
+            None => false,
+        };
+
+        let mut stats = self.stats.lock().await;
+        if stale != stats.commands_stale {
+            if stale {
+                tracing::warn!(
+                    "No policy command for {} ms, zeroing commands",
+                    self.config.command_timeout_ms
+                );
+            } else {
+                tracing::info!("Policy commands resumed");
+            }
+            stats.commands_stale = stale;
+        }
+        if stale {
+            HashMap::new()
+        } else {
+            commands.values.clone()
+        }
+    }
+
+    async fn step(&mut self) -> Result<()> {
+        let sensors = self.read_sensors().await?;
+        let commands = self.commands().await;
+        let observation = self
+            .config
+            .observation(&sensors, &self.last_action, &commands)?;
+
+        let mut inputs = HashMap::new();
+        inputs.insert(
+            self.config.observation_input.clone(),
+            Tensor {
+                values: observation,
+                shape: self.observation_shape.clone(),
+                ..Default::default()
+            },
+        );
+        let mut response = self
+            .inference
+            .forward(self.model_uid.clone(), inputs)
+            .await?;
+        if let Some(error) = response.error {
+            eyre::bail!("Forward failed: {}", error.message);
+        }
+        let output = response
+            .outputs
+            .remove(&self.config.action_output)
+            .ok_or_else(|| eyre::eyre!("Model did not return '{}'", self.config.action_output))?;
+        // Backends return types other than FLOAT32 in `raw_data`.
+        let mut actions = tensor::to_f32(&output)
+            .map_err(|e| eyre::eyre!("Output '{}': {}", self.config.action_output, e))?;
+
+        let commands = self.config.commands(&mut actions, self.action_scale)?;
+        self.last_action = actions;
+        if self.dry_run {
+            tracing::debug!("Dry run, not sending commands: {:?}", commands);
+            return Ok(());
+        }
+
+        check_results(self.actuator.command_actuators(commands).await?)
+    }
+
+    /// Applies the config's stop behavior to the driven joints.
+    async fn release(&self) -> Result<()> {
+        let actuator_ids = self.config.commanded_actuators();
+        match self.config.action.on_stop {
+            StopBehavior::Hold => {
+                let commands: Vec<ActuatorCommand> = self
+                    .actuator
+                    .get_actuators_state(actuator_ids)
+                    .await?
+                    .into_iter()
+                    .filter_map(|state| {
+                        Some(ActuatorCommand {
+                            actuator_id: state.actuator_id,
+                            position: Some(state.position?),
+                            velocity: None,
+                            torque: None,
+                        })
+                    })
+                    .collect();
+                check_results(self.actuator.command_actuators(commands).await?)
+            }
+            StopBehavior::DisableTorque => {
+                for actuator_id in actuator_ids {
+                    let response = self
+                        .actuator
+                        .configure_actuator(ConfigureActuatorRequest {
+                            actuator_id,
+                            torque_enabled: Some(false),
+                            ..Default::default()
+                        })
+                        .await?;
+                    if !response.success {
+                        let message = response.error.map(|e| e.message).unwrap_or_default();
+                        eyre::bail!(
+                            "Actuator {} did not disable torque: {}",
+                            actuator_id,
+                            message
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn run(mut self, mut stop: watch::Receiver<bool>) {
+        let period = Duration::from_secs_f64(1.0 / self.config.rate_hz);
+        let period_ms = period.as_secs_f64() * 1000.0;
+        let mut interval = tokio::time::interval(period);
+        // After an overrun, start again on schedule rather than bursting.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut steps: i64 = 0;
+        let error = loop {
+            if self.episode_length > 0 && steps >= self.episode_length as i64 {
+                break None;
+            }
+            let deadline = tokio::select! {
+                deadline = interval.tick() => deadline,
+                _ = stop.changed() => break None,
+            };
+            let start = Instant::now();
+            let jitter_ms = start
+                .saturating_duration_since(deadline.into_std())
+                .as_secs_f64()
+                * 1000.0;
+
+            let result = self.step().await;
+            let step_time_ms = start.elapsed().as_secs_f64() * 1000.0;
+            self.stats
+                .lock()
+                .await
+                .record(jitter_ms, step_time_ms, period_ms);
+            if let Err(e) = result {
+                break Some(e.to_string());
+            }
+            steps += 1;
+        };
+
+        if !self.dry_run {
+            if let Err(e) = self.release().await {
+                tracing::error!("Failed to release joints after policy: {}", e);
+            }
+        }
+
+        let policy_uuid = {
+            let mut stats = self.stats.lock().await;
+            stats.running = false;
+            stats.last_error = error.clone();
+            stats.policy_uuid.clone()
+        };
+        match &error {
+            Some(e) => tracing::error!(
+                "Policy {} stopped after {} steps: {}",
+                policy_uuid,
+                steps,
+                e
+            ),
+            None => tracing::info!("Policy {} finished after {} steps", policy_uuid, steps),
+        }
+
+        let event = PolicyEvent {
+            event: "end".to_string(),
+            policy_uuid: Some(policy_uuid),
+            action: self.model_uid.clone(),
+            action_scale: self.action_scale,
+            episode_length: self.episode_length,
+            dry_run: self.dry_run,
+            success: error.is_none(),
+            error,
+        };
+        Telemetry::publish_event("policy/event", &event).await;
+    }
+}
+
+fn check_results(results: Vec<ActionResult>) -> Result<()> {
+    for result in results {
+        if !result.success {
+            let message = result.error.map(|e| e.message).unwrap_or_default();
+            eyre::bail!(
+                "Actuator {} rejected command: {}",
+                result.actuator_id,
+                message
+            );
+        }
+    }
+    Ok(())
+}
+
+fn policy_error(message: String) -> Option<Error> {
+    Some(Error {
+        code: ErrorCode::InvalidArgument as i32,
+        message,
+    })
+}
+
+#[async_trait]
+impl Policy for PolicyRunner {
+    async fn start_policy(
+        &self,
+        action: String,
+        action_scale: f32,
+        episode_length: i32,
+        dry_run: bool,
+    ) -> Result<StartPolicyResponse> {
+        let mut run = self.run.lock().await;
+        if run.as_ref().is_some_and(|run| !run.task.is_finished()) {
+            return Ok(StartPolicyResponse {
+                policy_uuid: None,
+                error: policy_error("Policy is already running".to_string()),
+            });
+        }
+
+        let model_uid = action;
+        let prepared = match self.load_config(&model_uid) {
+            Ok(config) => self
+                .observation_shape(&model_uid, &config)
+                .await
+                .map(|shape| (config, shape)),
+            Err(e) => Err(e),
+        };
+        let (config, observation_shape) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                return Ok(StartPolicyResponse {
+                    policy_uuid: None,
+                    error: policy_error(e.to_string()),
+                })
+            }
+        };
+
+        let policy_uuid = Uuid::new_v4().to_string();
+        *self.commands.lock().await = Commands::default();
+        *self.stats.lock().await = RunStats {
+            policy_uuid: policy_uuid.clone(),
+            model_uid: model_uid.clone(),
+            action_scale,
+            episode_length,
+            dry_run,
+            rate_hz: config.rate_hz,
+            running: true,
+            ..Default::default()
+        };
+        tracing::info!(
+            "Starting policy {} with model {} at {} Hz{}",
+            policy_uuid,
+            model_uid,
+            config.rate_hz,
+            if dry_run { " (dry run)" } else { "" }
+        );
+
+        let command_names = config.command_names();
+        let (stop, stop_rx) = watch::channel(false);
+        let run_loop = RunLoop {
+            inference: self.inference.clone(),
+            imu: self.imu.clone(),
+            actuator: self.actuator.clone(),
+            stats: self.stats.clone(),
+            commands: self.commands.clone(),
+            observed_actuators: config.observed_actuators(),
+            last_action: vec![0.0; config.action_len()],
+            config,
+            model_uid,
+            observation_shape,
+            action_scale,
+            episode_length,
+            dry_run,
+        };
+        *run = Some(ActiveRun {
+            policy_uuid: policy_uuid.clone(),
+            command_names,
+            stop,
+            task: tokio::spawn(run_loop.run(stop_rx)),
+        });
+
+        Ok(StartPolicyResponse {
+            policy_uuid: Some(policy_uuid),
+            error: None,
+        })
+    }
+
+    async fn stop_policy(&self) -> Result<StopPolicyResponse> {
+        let mut run = self.run.lock().await;
+        let Some(active) = run.take().filter(|run| !run.task.is_finished()) else {
+            return Ok(StopPolicyResponse {
+                policy_uuid: None,
+                error: policy_error("Policy is not running".to_string()),
+            });
+        };
+
+        let _ = active.stop.send(true);
+        if let Err(e) = active.task.await {
+            tracing::error!("Policy {} task failed: {}", active.policy_uuid, e);
+        }
+
+        Ok(StopPolicyResponse {
+            policy_uuid: Some(active.policy_uuid),
+            error: None,
+        })
+    }
+
+    async fn get_state(&self) -> Result<GetStateResponse> {
+        Ok(GetStateResponse {
+            state: self.stats.lock().await.to_state(),
+            error: None,
+        })
+    }
+
+    async fn set_command(&self, commands: HashMap<String, f32>) -> Result<ActionResponse> {
+        let failure = |message: String| ActionResponse {
+            success: false,
+            error: policy_error(message),
+        };
+        let run = self.run.lock().await;
+        let Some(active) = run.as_ref().filter(|run| !run.task.is_finished()) else {
+            return Ok(failure("Policy is not running".to_string()));
+        };
+
+        let mut unknown: Vec<&String> = commands
+            .keys()
+            .filter(|name| !active.command_names.contains(*name))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            let mut expected: Vec<&String> = active.command_names.iter().collect();
+            expected.sort();
+            return Ok(failure(format!(
+                "Unknown commands {:?}, the policy takes {:?}",
+                unknown, expected
+            )));
+        }
+        if let Some((name, value)) = commands.iter().find(|(_, value)| !value.is_finite()) {
+            return Ok(failure(format!("Command '{}' is {}", name, value)));
+        }
+
+        *self.commands.lock().await = Commands {
+            values: commands,
+            received_at: Some(Instant::now()),
+        };
+        Ok(ActionResponse {
+            success: true,
+            error: None,
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use crate::kos_proto::telemetry::{AudioEvent, ForwardEvent, LedEvent, ModelEvent, PolicyEvent};
+use crate::telemetry::{TelemetryPayload, TelemetryRecord};
+use crate::telemetry_encoding::TelemetryData;
+use eyre::Result;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long written events may sit in the buffer before they are flushed.
+const EVENT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the event log sidecar path for a KRec file.
+pub fn events_path(output_path: impl AsRef<Path>) -> PathBuf {
+    output_path.as_ref().with_extension("events.jsonl")
+}
+
+/// One line of the event log: the telemetry payload plus its topic.
+#[derive(Serialize)]
+struct RecordedEvent<'a, T> {
+    topic: &'a str,
+    #[serde(flatten)]
+    payload: TelemetryPayload<T>,
+}
+
+/// Appends telemetry events (model loads, policy starts, audio sessions,
+/// LED writes, ...) to a JSON-lines file next to the recording, since KRec
+/// frames only hold actuator and IMU data.
+///
+/// Events are buffered; call [`EventLog::flush_if_due`] regularly and
+/// [`EventLog::flush`] when the recording stops.
+pub struct EventLog {
+    file: BufWriter<File>,
+    /// When the oldest unflushed event was written.
+    unflushed_since: Option<Instant>,
+}
+
+impl EventLog {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            unflushed_since: None,
+        })
+    }
+
+    /// Flushes if events have been buffered for longer than the flush
+    /// interval.
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        match self.unflushed_since {
+            Some(since) if since.elapsed() >= EVENT_FLUSH_INTERVAL => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.unflushed_since = None;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Writes `record` if it is an event. Returns whether it was.
+    pub fn append(&mut self, record: &TelemetryRecord) -> Result<bool> {
+        match record.topic.as_str() {
+            "inference/model" => self.write::<ModelEvent>(record)?,
+            "inference/forward" => self.write::<ForwardEvent>(record)?,
+            "policy/event" => self.write::<PolicyEvent>(record)?,
+            "sound/event" => self.write::<AudioEvent>(record)?,
+            "led/write" => self.write::<LedEvent>(record)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn write<T: TelemetryData>(&mut self, record: &TelemetryRecord) -> Result<()> {
+        let event = RecordedEvent {
+            topic: &record.topic,
+            payload: record.decode::<T>()?,
+        };
+        serde_json::to_writer(&mut self.file, &event)?;
+        self.file.write_all(b"\n")?;
+        self.unflushed_since.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! Converts KRec recordings into formats that generic analysis tools read.
+//!
+//! CSV export writes one long-format file per stream (one row per actuator
+//! per frame), which loads directly into pandas. MCAP export writes the
+//! frames as `kos_proto` messages with their protobuf schemas embedded, so
+//! Foxglove can open the file without any KOS-specific plugins.
+
+use crate::kos_proto::actuator::{
+    ActuatorCommand, ActuatorStateResponse, CommandActuatorsRequest, GetActuatorsStateResponse,
+};
+use crate::kos_proto::imu::{ImuValuesResponse, QuaternionResponse};
+use crate::kos_proto::FILE_DESCRIPTOR_SET;
+use crate::recording::read_journal;
+use eyre::{Result, WrapErr};
+use krec::{KRec, KRecFrame};
+use prost::Message;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Mcap,
+}
+
+/// Loads a finished KRec file, or a journal left behind by a crash.
+pub fn load_recording(path: impl AsRef<Path>) -> Result<KRec> {
+    let path = path.as_ref();
+    if path.extension().and_then(|e| e.to_str()) == Some("journal") {
+        return read_journal(path);
+    }
+
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| eyre::eyre!("Invalid path {}", path.display()))?;
+    KRec::load(path_str).wrap_err_with(|| format!("Failed to load {}", path.display()))
+}
+
+/// Where `export` writes to when no output is given: a directory named after
+/// the recording for CSV, or a `.mcap` file next to it.
+pub fn default_export_path(input: impl AsRef<Path>, format: ExportFormat) -> PathBuf {
+    let input = input.as_ref();
+    match format {
+        ExportFormat::Csv => input.with_extension(""),
+        ExportFormat::Mcap => input.with_extension("mcap"),
+    }
+}
+
+/// Loads `input` and exports it to `output` in the given format.
+pub fn export(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    format: ExportFormat,
+) -> Result<()> {
+    let krec = load_recording(input)?;
+    match format {
+        ExportFormat::Csv => export_csv(&krec, output).map(|_| ()),
+        ExportFormat::Mcap => export_mcap(&krec, output),
+    }
+}
+
+/// Writes `commands.csv`, `states.csv` and `imu.csv` into `output_dir` and
+/// returns their paths.
+pub fn export_csv(krec: &KRec, output_dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let output_dir = output_dir.as_ref();
+    std::fs::create_dir_all(output_dir)?;
+
+    let commands_path = output_dir.join("commands.csv");
+    let mut commands = csv_file(&commands_path, "actuator_id,position,velocity,torque")?;
+    let states_path = output_dir.join("states.csv");
+    let mut states = csv_file(
+        &states_path,
+        "actuator_id,online,position,velocity,torque,temperature,voltage,current",
+    )?;
+    let imu_path = output_dir.join("imu.csv");
+    let mut imu = csv_file(
+        &imu_path,
+        "accel_x,accel_y,accel_z,gyro_x,gyro_y,gyro_z,mag_x,mag_y,mag_z,quat_x,quat_y,quat_z,quat_w",
+    )?;
+
+    for frame in &krec.frames {
+        let prefix = frame_columns(frame);
+
+        for command in &frame.actuator_commands {
+            writeln!(
+                commands,
+                "{},{},{},{},{}",
+                prefix, command.actuator_id, command.position, command.velocity, command.torque
+            )?;
+        }
+
+        for state in &frame.actuator_states {
+            writeln!(
+                states,
+                "{},{},{},{},{},{},{},{},{}",
+                prefix,
+                state.actuator_id,
+                state.online,
+                optional(state.position),
+                optional(state.velocity),
+                optional(state.torque),
+                optional(state.temperature),
+                optional(state.voltage),
+                optional(state.current),
+            )?;
+        }
+
+        if let Some(values) = &frame.imu_values {
+            let vec3 = |v: Option<krec::Vec3>| match v {
+                Some(v) => format!("{},{},{}", v.x, v.y, v.z),
+                None => ",,".to_string(),
+            };
+            let quaternion = match values.quaternion {
+                Some(q) => format!("{},{},{},{}", q.x, q.y, q.z, q.w),
+                None => ",,,".to_string(),
+            };
+            writeln!(
+                imu,
+                "{},{},{},{},{}",
+                prefix,
+                vec3(values.accel),
+                vec3(values.gyro),
+                vec3(values.mag),
+                quaternion
+            )?;
+        }
+    }
+
+    for file in [&mut commands, &mut states, &mut imu] {
+        file.flush()?;
+    }
+    Ok(vec![commands_path, states_path, imu_path])
+}
+
+fn csv_file(path: &Path, columns: &str) -> Result<BufWriter<File>> {
+    let mut file = BufWriter::new(
+        File::create(path).wrap_err_with(|| format!("Failed to create {}", path.display()))?,
+    );
+    writeln!(
+        file,
+        "inference_step,real_timestamp,video_timestamp,video_frame_number,{}",
+        columns
+    )?;
+    Ok(file)
+}
+
+fn frame_columns(frame: &KRecFrame) -> String {
+    format!(
+        "{},{},{},{}",
+        frame.inference_step, frame.real_timestamp, frame.video_timestamp, frame.video_frame_number
+    )
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+const MCAP_MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_METADATA: u8 = 0x0C;
+const OP_DATA_END: u8 = 0x0F;
+
+/// Channels written by `export_mcap`, as (topic, protobuf message name).
+const MCAP_CHANNELS: [(&str, &str); 4] = [
+    ("/actuator/state", "kos.actuator.GetActuatorsStateResponse"),
+    ("/actuator/command", "kos.actuator.CommandActuatorsRequest"),
+    ("/imu/values", "kos.imu.IMUValuesResponse"),
+    ("/imu/quaternion", "kos.imu.QuaternionResponse"),
+];
+
+/// Writes the recording as an unchunked MCAP file. Frames are logged at their
+/// `real_timestamp` and the KRec header is stored as an MCAP metadata record.
+pub fn export_mcap(krec: &KRec, output_path: impl AsRef<Path>) -> Result<()> {
+    let output_path = output_path.as_ref();
+    let file = File::create(output_path)
+        .wrap_err_with(|| format!("Failed to create {}", output_path.display()))?;
+    let mut writer = McapWriter::new(BufWriter::new(file))?;
+
+    for (index, (topic, message_name)) in MCAP_CHANNELS.iter().enumerate() {
+        let id = index as u16 + 1;
+        writer.schema(id, message_name, FILE_DESCRIPTOR_SET)?;
+        writer.channel(id, id, topic)?;
+    }
+
+    let header = &krec.header;
+    writer.metadata(
+        "krec_header",
+        &[
+            ("uuid", header.uuid.clone()),
+            ("task", header.task.clone()),
+            ("robot_platform", header.robot_platform.clone()),
+            ("robot_serial", header.robot_serial.clone()),
+            ("start_timestamp", header.start_timestamp.to_string()),
+            ("end_timestamp", header.end_timestamp.to_string()),
+        ],
+    )?;
+
+    for frame in &krec.frames {
+        let time = frame.real_timestamp;
+
+        if !frame.actuator_states.is_empty() {
+            let message = GetActuatorsStateResponse {
+                states: frame
+                    .actuator_states
+                    .iter()
+                    .map(|state| ActuatorStateResponse {
+                        actuator_id: state.actuator_id,
+                        online: state.online,
+                        position: state.position,
+                        velocity: state.velocity,
+                        torque: state.torque,
+                        temperature: state.temperature,
+                        voltage: state.voltage,
+                        current: state.current,
+                        ..Default::default()
+                    })
+                    .collect(),
+            };
+            writer.message(1, time, &message.encode_to_vec())?;
+        }
+
+        if !frame.actuator_commands.is_empty() {
+            let message = CommandActuatorsRequest {
+                commands: frame
+                    .actuator_commands
+                    .iter()
+                    .map(|command| ActuatorCommand {
+                        actuator_id: command.actuator_id,
+                        position: Some(command.position as f64),
+                        velocity: Some(command.velocity as f64),
+                        torque: Some(command.torque as f64),
+                    })
+                    .collect(),
+            };
+            writer.message(2, time, &message.encode_to_vec())?;
+        }
+
+        if let Some(values) = &frame.imu_values {
+            if values.accel.is_some() || values.gyro.is_some() || values.mag.is_some() {
+                let accel = values.accel.unwrap_or_default();
+                let gyro = values.gyro.unwrap_or_default();
+                let message = ImuValuesResponse {
+                    accel_x: accel.x,
+                    accel_y: accel.y,
+                    accel_z: accel.z,
+                    gyro_x: gyro.x,
+                    gyro_y: gyro.y,
+                    gyro_z: gyro.z,
+                    mag_x: values.mag.map(|m| m.x),
+                    mag_y: values.mag.map(|m| m.y),
+                    mag_z: values.mag.map(|m| m.z),
+                    error: None,
+                };
+                writer.message(3, time, &message.encode_to_vec())?;
+            }
+
+            if let Some(q) = values.quaternion {
+                let message = QuaternionResponse {
+                    x: q.x,
+                    y: q.y,
+                    z: q.z,
+                    w: q.w,
+                    error: None,
+                };
+                writer.message(4, time, &message.encode_to_vec())?;
+            }
+        }
+    }
+
+    writer.finish()
+}
+
+/// Minimal MCAP writer: no chunking, compression or summary section, which
+/// readers handle by scanning the data section.
+struct McapWriter<W: Write> {
+    out: W,
+    sequences: [u32; MCAP_CHANNELS.len()],
+}
+
+impl<W: Write> McapWriter<W> {
+    fn new(mut out: W) -> Result<Self> {
+        out.write_all(MCAP_MAGIC)?;
+        let mut writer = Self {
+            out,
+            sequences: [0; MCAP_CHANNELS.len()],
+        };
+
+        let mut header = Vec::new();
+        put_string(&mut header, "");
+        put_string(&mut header, concat!("kos ", env!("CARGO_PKG_VERSION")));
+        writer.record(OP_HEADER, &header)?;
+        Ok(writer)
+    }
+
+    fn schema(&mut self, id: u16, name: &str, descriptor_set: &[u8]) -> Result<()> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&id.to_le_bytes());
+        put_string(&mut record, name);
+        put_string(&mut record, "protobuf");
+        put_bytes(&mut record, descriptor_set);
+        self.record(OP_SCHEMA, &record)
+    }
+
+    fn channel(&mut self, id: u16, schema_id: u16, topic: &str) -> Result<()> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&id.to_le_bytes());
+        record.extend_from_slice(&schema_id.to_le_bytes());
+        put_string(&mut record, topic);
+        put_string(&mut record, "protobuf");
+        put_map(&mut record, &[]);
+        self.record(OP_CHANNEL, &record)
+    }
+
+    fn metadata(&mut self, name: &str, entries: &[(&str, String)]) -> Result<()> {
+        let mut record = Vec::new();
+        put_string(&mut record, name);
+        put_map(&mut record, entries);
+        self.record(OP_METADATA, &record)
+    }
+
+    fn message(&mut self, channel_id: u16, time: u64, data: &[u8]) -> Result<()> {
+        let sequence = &mut self.sequences[channel_id as usize - 1];
+        let mut record = Vec::with_capacity(22 + data.len());
+        record.extend_from_slice(&channel_id.to_le_bytes());
+        record.extend_from_slice(&sequence.to_le_bytes());
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(data);
+        *sequence += 1;
+        self.record(OP_MESSAGE, &record)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        // A zero CRC means "not computed".
+        self.record(OP_DATA_END, &0u32.to_le_bytes())?;
+
+        let mut footer = Vec::new();
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u32.to_le_bytes());
+        self.record(OP_FOOTER, &footer)?;
+
+        self.out.write_all(MCAP_MAGIC)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn record(&mut self, opcode: u8, content: &[u8]) -> Result<()> {
+        self.out.write_all(&[opcode])?;
+        self.out.write_all(&(content.len() as u64).to_le_bytes())?;
+        self.out.write_all(content)?;
+        Ok(())
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_bytes(buf, value.as_bytes());
+}
+
+fn put_map(buf: &mut Vec<u8>, entries: &[(&str, String)]) {
+    let mut map = Vec::new();
+    for (key, value) in entries {
+        put_string(&mut map, key);
+        put_string(&mut map, value);
+    }
+    put_bytes(buf, &map);
+}
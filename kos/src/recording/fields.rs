@@ -0,0 +1,85 @@
+use crate::telemetry_types;
+use eyre::Result;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const FIELDS_MAGIC: &[u8; 4] = b"KCF1";
+
+/// Bits of a command field mask.
+pub const FIELD_POSITION: u8 = 1 << 0;
+pub const FIELD_VELOCITY: u8 = 1 << 1;
+pub const FIELD_TORQUE: u8 = 1 << 2;
+
+/// Returns the command field sidecar path for a KRec file.
+pub fn fields_path(output_path: impl AsRef<Path>) -> PathBuf {
+    output_path.as_ref().with_extension("fields")
+}
+
+/// Mask of the fields `command` set.
+pub fn command_fields(command: &telemetry_types::ActuatorCommand) -> u8 {
+    let mut fields = 0;
+    if command.position.is_some() {
+        fields |= FIELD_POSITION;
+    }
+    if command.velocity.is_some() {
+        fields |= FIELD_VELOCITY;
+    }
+    if command.torque.is_some() {
+        fields |= FIELD_TORQUE;
+    }
+    fields
+}
+
+/// Records which fields each actuator command set, one entry per journal
+/// frame, since KRec stores every command field as a plain float and an
+/// unset field reads back as zero.
+///
+/// Each entry is a little-endian `u16` count followed by one mask byte per
+/// command, in the frame's command order.
+pub struct FieldLog {
+    file: BufWriter<File>,
+}
+
+impl FieldLog {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(FIELDS_MAGIC)?;
+        Ok(Self { file })
+    }
+
+    /// Appends the masks of the next frame's commands.
+    pub fn append(&mut self, fields: &[u8]) -> Result<()> {
+        let count = u16::try_from(fields.len())
+            .map_err(|_| eyre::eyre!("Too many commands in one frame: {}", fields.len()))?;
+        self.file.write_all(&count.to_le_bytes())?;
+        self.file.write_all(fields)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the per-frame masks written by [`FieldLog`]. A torn last entry
+/// is dropped, so frames past the end have unknown fields.
+pub fn read_fields(path: impl AsRef<Path>) -> Result<Vec<Vec<u8>>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let Some(mut rest) = data.strip_prefix(FIELDS_MAGIC.as_slice()) else {
+        eyre::bail!("Not a command field file");
+    };
+
+    let mut frames = Vec::new();
+    while let Some((count, tail)) = rest.split_first_chunk::<2>() {
+        let count = u16::from_le_bytes(*count) as usize;
+        let Some((fields, tail)) = tail.split_at_checked(count) else {
+            break;
+        };
+        frames.push(fields.to_vec());
+        rest = tail;
+    }
+    Ok(frames)
+}
@@ -0,0 +1,612 @@
+use crate::config::kos_data_dir;
+use crate::hal::{
+    replay_clip_request, ClipAnnotation, ClipDownload, ClipInfo, KClipStartResponse,
+    KClipStopResponse, ListClipsResponse, Operation, ProcessManager, ReplayClipRequest,
+};
+use crate::kos_proto::common::{ActionResponse, Error, ErrorCode};
+use crate::recording::{
+    events_path, fields_path, journal_path, load_recording, metadata_path, read_fields,
+    read_header, recover_journal, start_replay, RecordingSources, ReplayOptions,
+};
+use crate::services::{OperationsServiceImpl, TelemetryLogger};
+use crate::time_sync;
+use async_trait::async_trait;
+use bytes::Bytes;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+const KREC_EXTENSION: &str = "krec";
+const ANNOTATION_EXTENSION: &str = "annotation.json";
+
+/// How often a running clip checks the storage limits.
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct KClipConfig {
+    /// Directory clips are written to, one `<uuid>.krec` per clip.
+    pub clips_dir: PathBuf,
+    /// Clips still running after this long are stopped automatically.
+    pub max_duration: Duration,
+    /// New clips are rejected, and a running clip is stopped, once the clips
+    /// directory holds this many bytes.
+    pub max_storage_bytes: Option<u64>,
+    /// Likewise once the filesystem holding the clips has less than this
+    /// much space left.
+    pub min_free_bytes: Option<u64>,
+}
+
+impl Default for KClipConfig {
+    fn default() -> Self {
+        Self {
+            clips_dir: kos_data_dir().join("clips"),
+            max_duration: Duration::from_secs(10 * 60),
+            max_storage_bytes: Some(4 * 1024 * 1024 * 1024),
+            min_free_bytes: Some(512 * 1024 * 1024),
+        }
+    }
+}
+
+/// On-disk form of a `ClipAnnotation`, stored next to the KRec file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StoredAnnotation {
+    task_labels: Vec<String>,
+    success: Option<bool>,
+    notes: String,
+}
+
+impl From<ClipAnnotation> for StoredAnnotation {
+    fn from(annotation: ClipAnnotation) -> Self {
+        Self {
+            task_labels: annotation.task_labels,
+            success: annotation.success,
+            notes: annotation.notes,
+        }
+    }
+}
+
+impl From<StoredAnnotation> for ClipAnnotation {
+    fn from(annotation: StoredAnnotation) -> Self {
+        Self {
+            task_labels: annotation.task_labels,
+            success: annotation.success,
+            notes: annotation.notes,
+        }
+    }
+}
+
+struct ActiveClip {
+    uuid: String,
+    action: String,
+    start_timestamp: u64,
+    output_path: PathBuf,
+    logger: TelemetryLogger,
+    /// Stops the clip at the maximum duration or when storage runs low.
+    watchdog: JoinHandle<()>,
+}
+
+impl ActiveClip {
+    /// Finalizes the recording and returns the clip UUID and output path.
+    async fn finish(self) -> Result<(String, PathBuf)> {
+        self.logger.stop().await?;
+        Ok((self.uuid, self.output_path))
+    }
+}
+
+/// Built-in `ProcessManager` that records K-Clips with the `TelemetryLogger`.
+pub struct KClipManager {
+    config: KClipConfig,
+    robot_name: String,
+    robot_serial: String,
+    sources: RecordingSources,
+    active: Arc<Mutex<Option<ActiveClip>>>,
+    operations: Option<Arc<OperationsServiceImpl>>,
+    replay: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl KClipManager {
+    /// Creates the clips directory and recovers any clip whose recording was
+    /// interrupted by a crash.
+    pub fn new(
+        robot_name: impl Into<String>,
+        robot_serial: impl Into<String>,
+        config: KClipConfig,
+        sources: RecordingSources,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&config.clips_dir)?;
+        recover_interrupted_clips(&config.clips_dir);
+
+        Ok(Self {
+            config,
+            robot_name: robot_name.into(),
+            robot_serial: robot_serial.into(),
+            sources,
+            active: Arc::new(Mutex::new(None)),
+            operations: None,
+            replay: Mutex::new(None),
+        })
+    }
+
+    /// Enables `ReplayClip`, which reports progress through long-running
+    /// operations.
+    pub fn with_operations(mut self, operations: Arc<OperationsServiceImpl>) -> Self {
+        self.operations = Some(operations);
+        self
+    }
+
+    pub fn config(&self) -> &KClipConfig {
+        &self.config
+    }
+
+    /// Returns the KRec path for a clip UUID.
+    pub fn clip_path(&self, clip_uuid: &str) -> PathBuf {
+        self.config
+            .clips_dir
+            .join(clip_uuid)
+            .with_extension(KREC_EXTENSION)
+    }
+
+    fn annotation_path(&self, clip_uuid: &str) -> PathBuf {
+        self.clip_path(clip_uuid)
+            .with_extension(ANNOTATION_EXTENSION)
+    }
+
+    /// Returns the path of a finished clip, rejecting anything that is not a
+    /// clip UUID so requests can't escape the clips directory.
+    fn finished_clip_path(&self, clip_uuid: &str) -> Result<PathBuf> {
+        Uuid::parse_str(clip_uuid).map_err(|_| eyre::eyre!("Invalid clip UUID {}", clip_uuid))?;
+        let path = self.clip_path(clip_uuid);
+        if !path.is_file() {
+            eyre::bail!("Clip {} not found", clip_uuid);
+        }
+        Ok(path)
+    }
+
+    fn load_annotation(&self, clip_uuid: &str) -> Option<ClipAnnotation> {
+        let data = std::fs::read(self.annotation_path(clip_uuid)).ok()?;
+        match serde_json::from_slice::<StoredAnnotation>(&data) {
+            Ok(annotation) => Some(annotation.into()),
+            Err(e) => {
+                tracing::warn!(
+                    "Ignoring unreadable annotation for clip {}: {}",
+                    clip_uuid,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Total size of everything in the clips directory.
+fn storage_used(clips_dir: &Path) -> Result<u64> {
+    let mut used = 0;
+    for entry in std::fs::read_dir(clips_dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            used += metadata.len();
+        }
+    }
+    Ok(used)
+}
+
+/// Space left for unprivileged writes on the filesystem holding `path`.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid out pointer.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Describes why no more clip data should be written, if the clips
+/// directory is over its quota or the filesystem is running out of space.
+fn storage_exhausted(config: &KClipConfig) -> Result<Option<String>> {
+    if let Some(quota) = config.max_storage_bytes {
+        let used = storage_used(&config.clips_dir)?;
+        if used >= quota {
+            return Ok(Some(format!(
+                "Clip storage is full ({} of {} bytes used)",
+                used, quota
+            )));
+        }
+    }
+    if let Some(min_free) = config.min_free_bytes {
+        let free = available_space(&config.clips_dir)?;
+        if free < min_free {
+            return Ok(Some(format!(
+                "Disk is almost full ({} bytes free, clips need {})",
+                free, min_free
+            )));
+        }
+    }
+    Ok(None)
+}
+
+fn error_response(code: ErrorCode, message: String) -> ActionResponse {
+    ActionResponse {
+        success: false,
+        error: Some(Error {
+            code: code as i32,
+            message,
+        }),
+    }
+}
+
+/// Replaces `path` through a temporary file in the same directory so readers
+/// never see a half-written file.
+fn write_replacing(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    let result = (|| -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    Ok(result?)
+}
+
+fn recover_interrupted_clips(clips_dir: &Path) {
+    let entries = match std::fs::read_dir(clips_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::warn!("Failed to scan {} for clips: {}", clips_dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(output_path) = path
+            .to_str()
+            .and_then(|p| p.strip_suffix(".journal"))
+            .map(PathBuf::from)
+        else {
+            continue;
+        };
+        if journal_path(&output_path) != path {
+            continue;
+        }
+        if let Err(e) = recover_journal(&path, &output_path) {
+            tracing::warn!("Failed to recover clip {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[async_trait]
+impl ProcessManager for KClipManager {
+    async fn start_kclip(&self, action: String) -> Result<KClipStartResponse> {
+        let mut active = self.active.lock().await;
+        if active.is_some() {
+            return Ok(KClipStartResponse {
+                clip_uuid: None,
+                error: Some(Error {
+                    code: ErrorCode::InvalidArgument as i32,
+                    message: "KClip is already started".to_string(),
+                }),
+                output_path: None,
+            });
+        }
+
+        if let Some(message) = storage_exhausted(&self.config)? {
+            return Ok(KClipStartResponse {
+                clip_uuid: None,
+                error: Some(Error {
+                    code: ErrorCode::ResourceExhausted as i32,
+                    message,
+                }),
+                output_path: None,
+            });
+        }
+
+        let uuid = Uuid::new_v4().to_string();
+        let output_path = self.clip_path(&uuid);
+        let start_timestamp = time_sync::wall_ns();
+        let logger = TelemetryLogger::new(
+            uuid.clone(),
+            action.clone(),
+            &output_path,
+            self.robot_name.clone(),
+            self.robot_serial.clone(),
+            self.sources.clone(),
+        )
+        .await?;
+
+        // Stop the clip automatically once it hits the maximum duration or
+        // storage runs low.
+        let max_duration = self.config.max_duration;
+        let config = self.config.clone();
+        let watchdog_active = self.active.clone();
+        let watchdog_uuid = uuid.clone();
+        let watchdog = tokio::spawn(async move {
+            let deadline = tokio::time::Instant::now() + max_duration;
+            let reason = loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => {
+                        break format!("reached the maximum duration of {:?}", max_duration);
+                    }
+                    _ = tokio::time::sleep(STORAGE_CHECK_INTERVAL) => {}
+                }
+                let config = config.clone();
+                match tokio::task::spawn_blocking(move || storage_exhausted(&config)).await {
+                    Ok(Ok(Some(message))) => break message,
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => tracing::warn!("Failed to check clip storage: {}", e),
+                    Err(e) => tracing::warn!("Failed to check clip storage: {}", e),
+                }
+            };
+
+            // The clip may have been stopped and another started while the
+            // storage check ran.
+            let clip = {
+                let mut active = watchdog_active.lock().await;
+                if active.as_ref().map(|clip| clip.uuid.as_str()) != Some(&watchdog_uuid) {
+                    return;
+                }
+                active.take()
+            };
+            let Some(clip) = clip else {
+                return;
+            };
+            match clip.finish().await {
+                Ok((uuid, _)) => tracing::warn!("KClip {} was stopped: {}", uuid, reason),
+                Err(e) => tracing::error!("Failed to stop KClip ({}): {}", reason, e),
+            }
+        });
+
+        tracing::info!("Started KClip {} at {}", uuid, output_path.display());
+        *active = Some(ActiveClip {
+            uuid: uuid.clone(),
+            action,
+            start_timestamp,
+            output_path: output_path.clone(),
+            logger,
+            watchdog,
+        });
+
+        Ok(KClipStartResponse {
+            clip_uuid: Some(uuid),
+            error: None,
+            output_path: Some(output_path.display().to_string()),
+        })
+    }
+
+    async fn stop_kclip(&self) -> Result<KClipStopResponse> {
+        let clip = self.active.lock().await.take();
+        match clip {
+            Some(clip) => {
+                clip.watchdog.abort();
+                let (uuid, output_path) = clip.finish().await?;
+                tracing::info!("Stopped KClip {}", uuid);
+                Ok(KClipStopResponse {
+                    clip_uuid: Some(uuid),
+                    error: None,
+                    output_path: Some(output_path.display().to_string()),
+                })
+            }
+            None => Ok(KClipStopResponse {
+                clip_uuid: None,
+                error: Some(Error {
+                    code: ErrorCode::InvalidArgument as i32,
+                    message: "KClip is not running".to_string(),
+                }),
+                output_path: None,
+            }),
+        }
+    }
+
+    async fn list_clips(&self) -> Result<ListClipsResponse> {
+        let mut clips = Vec::new();
+
+        if let Some(clip) = self.active.lock().await.as_ref() {
+            clips.push(ClipInfo {
+                clip_uuid: clip.uuid.clone(),
+                action: clip.action.clone(),
+                size_bytes: std::fs::metadata(journal_path(&clip.output_path))
+                    .map(|m| m.len())
+                    .unwrap_or_default(),
+                start_timestamp: clip.start_timestamp,
+                end_timestamp: 0,
+                recording: true,
+                annotation: self.load_annotation(&clip.uuid),
+            });
+        }
+
+        for entry in std::fs::read_dir(&self.config.clips_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(KREC_EXTENSION) {
+                continue;
+            }
+            let Some(clip_uuid) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+
+            let header = match read_header(&path) {
+                Ok(header) => header,
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable clip {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            clips.push(ClipInfo {
+                clip_uuid: clip_uuid.to_string(),
+                action: header.task,
+                size_bytes: std::fs::metadata(&path)?.len(),
+                start_timestamp: header.start_timestamp,
+                end_timestamp: header.end_timestamp,
+                recording: false,
+                annotation: self.load_annotation(clip_uuid),
+            });
+        }
+
+        clips.sort_by_key(|clip| clip.start_timestamp);
+
+        Ok(ListClipsResponse {
+            clips,
+            used_bytes: storage_used(&self.config.clips_dir)?,
+            quota_bytes: self.config.max_storage_bytes,
+            error: None,
+        })
+    }
+
+    async fn download_clip(&self, clip_uuid: String, chunk_size: usize) -> Result<ClipDownload> {
+        let path = self.finished_clip_path(&clip_uuid)?;
+        let file = tokio::fs::File::open(&path).await?;
+        let total_size = file.metadata().await?.len();
+
+        let stream = futures::stream::try_unfold(file, move |mut file| async move {
+            let mut buffer = vec![0u8; chunk_size];
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok::<_, eyre::Report>(None);
+            }
+            buffer.truncate(read);
+            Ok(Some((Bytes::from(buffer), file)))
+        });
+
+        Ok(ClipDownload {
+            total_size,
+            stream: Box::pin(stream),
+        })
+    }
+
+    async fn delete_clip(&self, clip_uuid: String) -> Result<ActionResponse> {
+        if let Some(clip) = self.active.lock().await.as_ref() {
+            if clip.uuid == clip_uuid {
+                return Ok(error_response(
+                    ErrorCode::InvalidArgument,
+                    "Cannot delete a clip that is still recording".to_string(),
+                ));
+            }
+        }
+
+        let path = match self.finished_clip_path(&clip_uuid) {
+            Ok(path) => path,
+            Err(e) => return Ok(error_response(ErrorCode::InvalidArgument, e.to_string())),
+        };
+
+        std::fs::remove_file(&path)?;
+        for sidecar in [
+            metadata_path(&path),
+            events_path(&path),
+            fields_path(&path),
+            self.annotation_path(&clip_uuid),
+        ] {
+            match std::fs::remove_file(&sidecar) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to remove {}: {}", sidecar.display(), e),
+            }
+        }
+
+        tracing::info!("Deleted KClip {}", clip_uuid);
+        Ok(ActionResponse {
+            success: true,
+            error: None,
+        })
+    }
+
+    async fn annotate_clip(
+        &self,
+        clip_uuid: String,
+        annotation: ClipAnnotation,
+    ) -> Result<ActionResponse> {
+        let is_active = self
+            .active
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(|clip| clip.uuid == clip_uuid);
+        if !is_active {
+            if let Err(e) = self.finished_clip_path(&clip_uuid) {
+                return Ok(error_response(ErrorCode::InvalidArgument, e.to_string()));
+            }
+        }
+
+        let annotation = StoredAnnotation::from(annotation);
+        write_replacing(
+            &self.annotation_path(&clip_uuid),
+            &serde_json::to_vec_pretty(&annotation)?,
+        )?;
+
+        Ok(ActionResponse {
+            success: true,
+            error: None,
+        })
+    }
+
+    async fn replay_clip(&self, request: ReplayClipRequest) -> Result<Operation> {
+        let actuator = self
+            .sources
+            .actuator
+            .clone()
+            .ok_or_else(|| eyre::eyre!("Replaying clips needs an actuator service"))?;
+        let operations = self
+            .operations
+            .clone()
+            .ok_or_else(|| eyre::eyre!("Replaying clips needs the operations service"))?;
+
+        let mut replay = self.replay.lock().await;
+        if replay.as_ref().is_some_and(|task| !task.is_finished()) {
+            eyre::bail!("A replay is already running");
+        }
+
+        let (source, path) = match request.source {
+            Some(replay_clip_request::Source::ClipUuid(clip_uuid)) => {
+                let path = self.finished_clip_path(&clip_uuid)?;
+                (clip_uuid, path)
+            }
+            Some(replay_clip_request::Source::Path(path)) => (path.clone(), PathBuf::from(path)),
+            None => eyre::bail!("A clip UUID or path is required"),
+        };
+        let (krec, fields) = tokio::task::spawn_blocking(move || -> Result<_> {
+            // Clips recorded before field logging replay as unknown fields.
+            let fields = match read_fields(fields_path(&path)) {
+                Ok(fields) => fields,
+                Err(e) => {
+                    tracing::debug!("No command fields for {}: {}", path.display(), e);
+                    vec![]
+                }
+            };
+            Ok((load_recording(path)?, fields))
+        })
+        .await??;
+
+        let options = ReplayOptions {
+            speed: request.speed.unwrap_or(1.0),
+            dry_run: request.dry_run,
+            actuator_ids: request.actuator_ids,
+            allow_unknown_fields: request.allow_unknown_fields,
+        };
+        tracing::info!("Replaying {} with {:?}", source, options);
+
+        let name = format!("operations/replay_clip/{}", Uuid::new_v4());
+        let (operation, task) =
+            start_replay(krec, fields, source, actuator, options, operations, name).await?;
+        *replay = Some(task);
+
+        Ok(operation)
+    }
+}
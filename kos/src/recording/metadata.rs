@@ -0,0 +1,258 @@
+use crate::hal::{get_models_info_request, Actuator, GetModelsInfoRequest, Inference, Policy};
+use crate::policy_config::PolicyConfig;
+use eyre::Result;
+use krec::ActuatorConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Services queried once when a recording starts so the recording describes
+/// the robot it was made on.
+#[derive(Clone, Default)]
+pub struct RecordingSources {
+    pub actuator: Option<Arc<dyn Actuator>>,
+    pub policy: Option<Arc<dyn Policy>>,
+    pub inference: Option<Arc<dyn Inference>>,
+    /// Actuators to describe. Empty means whatever the platform reports.
+    pub actuator_ids: Vec<u32>,
+    /// Joint names keyed by actuator ID. These win over names found when
+    /// the recording starts.
+    pub actuator_names: HashMap<u32, String>,
+    /// Policy configs, read for the running policy's joint names.
+    pub policy_config_dir: Option<PathBuf>,
+}
+
+/// Everything about a recording that does not fit in `KRecHeader`, stored as
+/// JSON next to the KRec file.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RecordingMetadata {
+    pub kos_version: String,
+    pub robot_platform: String,
+    pub robot_serial: String,
+    pub actuators: Vec<ActuatorMetadata>,
+    /// State reported by the policy service when recording started.
+    pub policy_state: HashMap<String, String>,
+    /// Models loaded when recording started, the running policy's first.
+    pub model_uids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ActuatorMetadata {
+    pub actuator_id: u32,
+    pub name: Option<String>,
+    pub kp: Option<f64>,
+    pub kd: Option<f64>,
+    pub ki: Option<f64>,
+    pub max_torque: Option<f64>,
+    pub min_position: Option<f64>,
+    pub max_position: Option<f64>,
+    pub torque_enabled: Option<bool>,
+    /// Raw parameter dump from the platform (model, firmware version, ...).
+    pub parameters: serde_json::Value,
+}
+
+/// Returns the metadata sidecar path for a KRec file.
+pub fn metadata_path(output_path: impl AsRef<Path>) -> PathBuf {
+    output_path.as_ref().with_extension("meta.json")
+}
+
+impl RecordingMetadata {
+    /// Queries the available services. Failures are logged and leave the
+    /// corresponding fields empty, so recording never fails on metadata.
+    pub async fn collect(
+        sources: &RecordingSources,
+        robot_platform: &str,
+        robot_serial: &str,
+    ) -> Self {
+        let mut metadata = RecordingMetadata {
+            kos_version: env!("CARGO_PKG_VERSION").to_string(),
+            robot_platform: robot_platform.to_string(),
+            robot_serial: robot_serial.to_string(),
+            ..Default::default()
+        };
+
+        if let Some(policy) = &sources.policy {
+            match policy.get_state().await {
+                Ok(state) => metadata.policy_state = state.state,
+                Err(e) => tracing::warn!("Failed to get policy state for recording: {}", e),
+            }
+        }
+
+        if let Some(actuator) = &sources.actuator {
+            let names = policy_joint_names(sources, &metadata.policy_state);
+            metadata.actuators = collect_actuators(actuator.as_ref(), sources, names).await;
+        }
+
+        // The built-in policy runner reports its model as the action.
+        if metadata.policy_state.get("running").map(String::as_str) == Some("true") {
+            if let Some(model_uid) = metadata.policy_state.get("action") {
+                metadata.model_uids.push(model_uid.clone());
+            }
+        }
+
+        if let Some(inference) = &sources.inference {
+            let request = GetModelsInfoRequest {
+                filter: Some(get_models_info_request::Filter::All(true)),
+            };
+            match inference.get_models_info(request).await {
+                Ok(info) => {
+                    for model in info.models {
+                        if model.loaded && !metadata.model_uids.contains(&model.uid) {
+                            metadata.model_uids.push(model.uid);
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to get models info for recording: {}", e),
+            }
+        }
+
+        metadata
+    }
+
+    /// Converts the per-actuator metadata into KRec header configs.
+    pub fn actuator_configs(&self) -> Vec<ActuatorConfig> {
+        self.actuators
+            .iter()
+            .map(|actuator| ActuatorConfig {
+                actuator_id: actuator.actuator_id,
+                name: actuator.name.clone(),
+                kp: actuator.kp,
+                kd: actuator.kd,
+                ki: actuator.ki,
+                max_torque: actuator.max_torque,
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+}
+
+/// Joint names from the running policy's config, if there is one.
+fn policy_joint_names(
+    sources: &RecordingSources,
+    policy_state: &HashMap<String, String>,
+) -> HashMap<u32, String> {
+    let (Some(dir), Some("true")) = (
+        &sources.policy_config_dir,
+        policy_state.get("running").map(String::as_str),
+    ) else {
+        return HashMap::new();
+    };
+    let Some(model_uid) = policy_state
+        .get("action")
+        .filter(|uid| !uid.is_empty() && !uid.contains(['/', '\\', '.']))
+    else {
+        return HashMap::new();
+    };
+
+    match PolicyConfig::load(dir.join(model_uid).with_extension("json")) {
+        Ok(config) => config
+            .joints
+            .into_iter()
+            .map(|joint| (joint.actuator_id, joint.name))
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Failed to read policy config for recording: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Name from the platform's actuator parameters, if it reports one.
+fn parameter_name(parameters: &prost_types::Struct) -> Option<String> {
+    match parameters.fields.get("name")?.kind.as_ref()? {
+        prost_types::value::Kind::StringValue(name) if !name.is_empty() => Some(name.clone()),
+        _ => None,
+    }
+}
+
+async fn collect_actuators(
+    actuator: &dyn Actuator,
+    sources: &RecordingSources,
+    mut names: HashMap<u32, String>,
+) -> Vec<ActuatorMetadata> {
+    let states = match actuator
+        .get_actuators_state(sources.actuator_ids.clone())
+        .await
+    {
+        Ok(states) => states,
+        Err(e) => {
+            tracing::warn!("Failed to get actuator states for recording: {}", e);
+            return vec![];
+        }
+    };
+
+    let ids = states.iter().map(|state| state.actuator_id).collect();
+    let mut parameters: HashMap<u32, prost_types::Struct> = match actuator.get_parameters(ids).await
+    {
+        Ok(parameters) => parameters.into_iter().collect(),
+        Err(e) => {
+            tracing::warn!("Failed to get actuator parameters for recording: {}", e);
+            HashMap::new()
+        }
+    };
+
+    for (id, params) in &parameters {
+        if let Some(name) = parameter_name(params) {
+            names.insert(*id, name);
+        }
+    }
+    names.extend(
+        sources
+            .actuator_names
+            .iter()
+            .map(|(id, name)| (*id, name.clone())),
+    );
+
+    states
+        .into_iter()
+        .map(|state| ActuatorMetadata {
+            actuator_id: state.actuator_id,
+            name: names.remove(&state.actuator_id),
+            kp: state.kp,
+            kd: state.kd,
+            ki: state.ki,
+            max_torque: state.max_torque,
+            min_position: state.min_position,
+            max_position: state.max_position,
+            torque_enabled: state.torque_enabled,
+            parameters: parameters
+                .remove(&state.actuator_id)
+                .map(struct_to_json)
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn struct_to_json(value: prost_types::Struct) -> serde_json::Value {
+    serde_json::Value::Object(
+        value
+            .fields
+            .into_iter()
+            .map(|(key, value)| (key, value_to_json(value)))
+            .collect(),
+    )
+}
+
+fn value_to_json(value: prost_types::Value) -> serde_json::Value {
+    use prost_types::value::Kind;
+
+    match value.kind {
+        Some(Kind::NullValue(_)) | None => serde_json::Value::Null,
+        Some(Kind::NumberValue(n)) => serde_json::json!(n),
+        Some(Kind::StringValue(s)) => serde_json::Value::String(s),
+        Some(Kind::BoolValue(b)) => serde_json::Value::Bool(b),
+        Some(Kind::StructValue(s)) => struct_to_json(s),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.into_iter().map(value_to_json).collect())
+        }
+    }
+}
@@ -0,0 +1,15 @@
+mod events;
+mod export;
+mod fields;
+mod kclip;
+mod metadata;
+mod replay;
+mod writer;
+
+pub use events::*;
+pub use export::*;
+pub use fields::*;
+pub use kclip::*;
+pub use metadata::*;
+pub use replay::*;
+pub use writer::*;
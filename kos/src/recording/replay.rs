@@ -0,0 +1,288 @@
+use crate::hal::{Actuator, ActuatorCommand, ActuatorDeviation, Operation, ReplayClipMetadata};
+use crate::kos_proto::common::{Error, ErrorCode};
+use crate::recording::{FIELD_POSITION, FIELD_TORQUE, FIELD_VELOCITY};
+use crate::services::OperationsServiceImpl;
+use eyre::Result;
+use krec::{KRec, KRecFrame};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+pub const REPLAY_METADATA_TYPE_URL: &str =
+    "type.googleapis.com/kos.processmanager.ReplayClipMetadata";
+
+/// How often the operation metadata is refreshed while a replay runs.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    /// Playback speed multiplier; 2.0 replays twice as fast.
+    pub speed: f64,
+    /// Read live states without commanding the actuators.
+    pub dry_run: bool,
+    /// Actuators to replay. Empty means every actuator in the recording.
+    pub actuator_ids: Vec<u32>,
+    /// Command frames whose set fields were not recorded, treating zero
+    /// fields as unset. Otherwise such a clip is refused.
+    pub allow_unknown_fields: bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            dry_run: false,
+            actuator_ids: vec![],
+            allow_unknown_fields: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DeviationStats {
+    samples: u64,
+    total_error: f64,
+    max_error: f64,
+}
+
+/// Replays a recording's actuator commands with their original spacing and
+/// compares the live actuator states against the recorded ones. The replay
+/// fails on the first command an actuator rejects.
+///
+/// Commands carry only the fields the original command set, as recorded in
+/// the clip's field sidecar. Where that is unknown, zero fields are taken as
+/// unset, which is lossy for commands that asked for exactly zero.
+struct Replay {
+    frames: Vec<KRecFrame>,
+    /// Per-frame command field masks; see [`crate::recording::FieldLog`].
+    fields: Vec<Vec<u8>>,
+    actuator: Arc<dyn Actuator>,
+    options: ReplayOptions,
+    filter: HashSet<u32>,
+    deviations: BTreeMap<u32, DeviationStats>,
+    metadata: ReplayClipMetadata,
+}
+
+impl Replay {
+    fn wants(&self, actuator_id: u32) -> bool {
+        self.filter.is_empty() || self.filter.contains(&actuator_id)
+    }
+
+    /// The fields each of a frame's commands set, if recorded.
+    fn frame_fields(&self, index: usize, frame: &KRecFrame) -> Option<&[u8]> {
+        self.fields
+            .get(index)
+            .filter(|fields| fields.len() == frame.actuator_commands.len())
+            .map(Vec::as_slice)
+    }
+
+    async fn replay_frame(&mut self, index: usize, frame: &KRecFrame) -> Result<()> {
+        if !self.options.dry_run {
+            let fields = self.frame_fields(index, frame);
+            let commands: Vec<_> = frame
+                .actuator_commands
+                .iter()
+                .enumerate()
+                .filter(|(_, command)| self.wants(command.actuator_id))
+                .filter_map(|(i, command)| {
+                    let set = match fields {
+                        Some(fields) => fields[i],
+                        None => guess_fields(command),
+                    };
+                    (set != 0).then(|| ActuatorCommand {
+                        actuator_id: command.actuator_id,
+                        position: (set & FIELD_POSITION != 0).then_some(command.position as f64),
+                        velocity: (set & FIELD_VELOCITY != 0)
+                            .then_some(command.velocity as f64 * self.options.speed),
+                        torque: (set & FIELD_TORQUE != 0).then_some(command.torque as f64),
+                    })
+                })
+                .collect();
+            if !commands.is_empty() {
+                for result in self.actuator.command_actuators(commands).await? {
+                    if !result.success {
+                        let message = result.error.map(|e| e.message).unwrap_or_default();
+                        eyre::bail!(
+                            "Actuator {} rejected replayed command: {}",
+                            result.actuator_id,
+                            message
+                        );
+                    }
+                }
+            }
+        }
+
+        let recorded: Vec<_> = frame
+            .actuator_states
+            .iter()
+            .filter(|state| self.wants(state.actuator_id))
+            .filter_map(|state| Some((state.actuator_id, state.position?)))
+            .collect();
+        if recorded.is_empty() {
+            return Ok(());
+        }
+
+        let ids = recorded.iter().map(|(id, _)| *id).collect();
+        let live = self.actuator.get_actuators_state(ids).await?;
+        for (actuator_id, recorded_position) in recorded {
+            let Some(position) = live
+                .iter()
+                .find(|state| state.actuator_id == actuator_id)
+                .and_then(|state| state.position)
+            else {
+                continue;
+            };
+
+            let error = (position - recorded_position).abs();
+            let stats = self.deviations.entry(actuator_id).or_default();
+            stats.samples += 1;
+            stats.total_error += error;
+            stats.max_error = stats.max_error.max(error);
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> ReplayClipMetadata {
+        self.metadata.deviations = self
+            .deviations
+            .iter()
+            .map(|(&actuator_id, stats)| ActuatorDeviation {
+                actuator_id,
+                samples: stats.samples,
+                mean_position_error: stats.total_error / stats.samples.max(1) as f64,
+                max_position_error: stats.max_error,
+            })
+            .collect();
+        self.metadata.clone()
+    }
+
+    async fn run(&mut self, operations: &OperationsServiceImpl, name: &str) -> Result<()> {
+        let frames = std::mem::take(&mut self.frames);
+        let first_timestamp = frames.first().map(|f| f.real_timestamp).unwrap_or_default();
+        let start = Instant::now();
+        let mut last_progress = Instant::now();
+
+        for (index, frame) in frames.iter().enumerate() {
+            let offset = frame.real_timestamp.saturating_sub(first_timestamp);
+            let offset = Duration::from_nanos(offset).div_f64(self.options.speed);
+            tokio::time::sleep_until(start + offset).await;
+
+            self.replay_frame(index, frame).await?;
+            self.metadata.frames_replayed += 1;
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                if let Err(e) = operations
+                    .update_metadata(name, self.snapshot(), false)
+                    .await
+                {
+                    tracing::warn!("Failed to update replay progress: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Field mask for a command whose set fields were not recorded.
+fn guess_fields(command: &krec::ActuatorCommand) -> u8 {
+    let mut fields = 0;
+    if command.position != 0.0 {
+        fields |= FIELD_POSITION;
+    }
+    if command.velocity != 0.0 {
+        fields |= FIELD_VELOCITY;
+    }
+    if command.torque != 0.0 {
+        fields |= FIELD_TORQUE;
+    }
+    fields
+}
+
+/// Registers a `ReplayClip` operation named `name` and replays `krec` in
+/// the background. `fields` holds the per-frame command field masks from
+/// [`crate::recording::read_fields`]. The operation is marked done when the
+/// replay finishes or fails; progress and tracking errors are reported in
+/// its metadata.
+pub async fn start_replay(
+    krec: KRec,
+    fields: Vec<Vec<u8>>,
+    source: String,
+    actuator: Arc<dyn Actuator>,
+    options: ReplayOptions,
+    operations: Arc<OperationsServiceImpl>,
+    name: String,
+) -> Result<(Operation, JoinHandle<()>)> {
+    let metadata = ReplayClipMetadata {
+        source,
+        status: "IN_PROGRESS".to_string(),
+        dry_run: options.dry_run,
+        speed: options.speed,
+        total_frames: krec.frames.len() as u64,
+        ..Default::default()
+    };
+
+    let mut replay = Replay {
+        frames: krec.frames,
+        fields,
+        actuator,
+        filter: options.actuator_ids.iter().copied().collect(),
+        options,
+        deviations: BTreeMap::new(),
+        metadata: metadata.clone(),
+    };
+
+    if !replay.options.dry_run && !replay.options.allow_unknown_fields {
+        let unknown = replay.frames.iter().enumerate().find(|(index, frame)| {
+            frame
+                .actuator_commands
+                .iter()
+                .any(|command| replay.wants(command.actuator_id))
+                && replay.frame_fields(*index, frame).is_none()
+        });
+        if let Some((index, _)) = unknown {
+            eyre::bail!(
+                "The clip does not record which command fields were set from frame {} on; \
+                 allow unknown fields to replay it with zero fields taken as unset",
+                index
+            );
+        }
+    }
+
+    let operation = operations
+        .create(name.clone(), metadata, REPLAY_METADATA_TYPE_URL)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to create operation: {}", e))?;
+
+    let task = tokio::spawn(async move {
+        let result = replay.run(&operations, &name).await;
+
+        let mut metadata = replay.snapshot();
+        match result {
+            Ok(()) => {
+                tracing::info!(
+                    "Replayed {} frames from {}",
+                    metadata.frames_replayed,
+                    metadata.source
+                );
+                metadata.status = "SUCCEEDED".to_string();
+            }
+            Err(e) => {
+                tracing::error!("Replay of {} failed: {}", metadata.source, e);
+                metadata.status = "FAILED".to_string();
+                metadata.error = Some(Error {
+                    code: ErrorCode::HardwareFailure as i32,
+                    message: e.to_string(),
+                });
+            }
+        }
+
+        if let Err(e) = operations.update_metadata(&name, metadata, true).await {
+            tracing::warn!("Failed to finish replay operation: {}", e);
+        }
+    });
+
+    Ok((operation, task))
+}
@@ -0,0 +1,304 @@
+//! Append-only KRec journal.
+//!
+//! Frames are appended to `<output>.journal` as they arrive and synced to disk
+//! in small chunks, so a crash or power loss costs at most one chunk. Each
+//! record is `kind (u8) | len (u32 LE) | crc32 (u32 LE) | payload`, and a
+//! reader stops at the first truncated or corrupt record.
+//!
+//! When recording stops, the journal is streamed into a regular KRec file
+//! (the length-prefixed header followed by length-prefixed frames that
+//! `KRec::save` writes) and removed.
+
+use eyre::{Result, WrapErr};
+use krec::{KRec, KRecFrame, KRecHeader};
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"KRECJNL1";
+const JOURNAL_EXTENSION: &str = "journal";
+
+/// Anything longer than this is treated as a corrupt length prefix.
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+const RECORD_HEADER: u8 = 1;
+const RECORD_FRAME: u8 = 2;
+
+/// Controls how often buffered frames are forced to disk.
+#[derive(Clone, Debug)]
+pub struct WriterOptions {
+    /// Sync after this many frames have been appended.
+    pub chunk_frames: usize,
+    /// Sync at least this often while frames are arriving.
+    pub chunk_interval: Duration,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            chunk_frames: 50,
+            chunk_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+pub struct KRecWriter {
+    file: BufWriter<File>,
+    journal_path: PathBuf,
+    options: WriterOptions,
+    pending_frames: usize,
+    frames_written: u64,
+    last_sync: Instant,
+}
+
+/// Returns the journal path used while recording to `output_path`.
+pub fn journal_path(output_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = output_path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(JOURNAL_EXTENSION);
+    PathBuf::from(path)
+}
+
+impl KRecWriter {
+    /// Starts a new journal next to `output_path` and writes `header` to it.
+    pub fn create(
+        output_path: impl AsRef<Path>,
+        header: &KRecHeader,
+        options: WriterOptions,
+    ) -> Result<Self> {
+        let journal_path = journal_path(output_path);
+        if let Some(parent) = journal_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&journal_path)
+            .wrap_err_with(|| format!("Failed to create {}", journal_path.display()))?;
+
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            journal_path,
+            options,
+            pending_frames: 0,
+            frames_written: 0,
+            last_sync: Instant::now(),
+        };
+        writer.file.write_all(JOURNAL_MAGIC)?;
+        writer.write_record(RECORD_HEADER, &header.encode_to_vec())?;
+        writer.sync()?;
+
+        Ok(writer)
+    }
+
+    /// Appends a frame, syncing to disk whenever a chunk fills up.
+    pub fn write_frame(&mut self, frame: &KRecFrame) -> Result<()> {
+        self.write_record(RECORD_FRAME, &frame.encode_to_vec())?;
+        self.frames_written += 1;
+        self.pending_frames += 1;
+
+        if self.pending_frames >= self.options.chunk_frames
+            || self.last_sync.elapsed() >= self.options.chunk_interval
+        {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Records an updated header (e.g. once actuator configs or the end
+    /// timestamp are known). The last header in the journal wins.
+    pub fn write_header(&mut self, header: &KRecHeader) -> Result<()> {
+        self.write_record(RECORD_HEADER, &header.encode_to_vec())?;
+        self.sync()
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.frames_written
+    }
+
+    /// Flushes buffered records and syncs the journal to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.pending_frames = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Writes the final header, converts the journal into a KRec file at
+    /// `output_path` and removes the journal. Returns the number of frames.
+    pub fn finish(mut self, header: &KRecHeader, output_path: impl AsRef<Path>) -> Result<u64> {
+        self.write_header(header)?;
+        drop(self.file);
+
+        let frames = convert_journal(&self.journal_path, output_path)?;
+        std::fs::remove_file(&self.journal_path)?;
+        Ok(frames)
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let len = u32::try_from(payload.len()).wrap_err("KRec record is too large")?;
+        self.file.write_all(&[kind])?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file
+            .write_all(&crc32fast::hash(payload).to_le_bytes())?;
+        self.file.write_all(payload)?;
+        Ok(())
+    }
+}
+
+/// Reads records from a journal, stopping quietly at a truncated or corrupt tail.
+struct JournalReader {
+    reader: BufReader<File>,
+}
+
+impl JournalReader {
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+        );
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != JOURNAL_MAGIC {
+            eyre::bail!("{} is not a KRec journal", path.display());
+        }
+        Ok(Self { reader })
+    }
+
+    fn next_record(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        let mut prefix = [0u8; 9];
+        match self.reader.read_exact(&mut prefix) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let kind = prefix[0];
+        let len = u32::from_le_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+        let crc = u32::from_le_bytes([prefix[5], prefix[6], prefix[7], prefix[8]]);
+        if len > MAX_RECORD_LEN {
+            tracing::warn!("Discarding corrupt KRec journal tail");
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; len];
+        match self.reader.read_exact(&mut payload) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        if crc32fast::hash(&payload) != crc || !matches!(kind, RECORD_HEADER | RECORD_FRAME) {
+            tracing::warn!("Discarding corrupt KRec journal tail");
+            return Ok(None);
+        }
+        Ok(Some((kind, payload)))
+    }
+}
+
+/// Streams a journal into a KRec file without holding every frame in memory.
+fn convert_journal(journal: &Path, output_path: impl AsRef<Path>) -> Result<u64> {
+    // The header has to come first in the output, but the most recent one is
+    // at the end of the journal, so take a first pass to find it.
+    let mut header = None;
+    let mut last_timestamp = 0;
+    let mut reader = JournalReader::open(journal)?;
+    while let Some((kind, payload)) = reader.next_record()? {
+        match kind {
+            RECORD_HEADER => header = Some(KRecHeader::decode(payload.as_slice())?),
+            _ => {
+                if let Ok(frame) = KRecFrame::decode(payload.as_slice()) {
+                    last_timestamp = frame.real_timestamp;
+                }
+            }
+        }
+    }
+    let mut header = header.ok_or_else(|| eyre::eyre!("KRec journal has no header"))?;
+    if header.end_timestamp == 0 {
+        header.end_timestamp = last_timestamp;
+    }
+
+    let output_path = output_path.as_ref();
+    let mut output = BufWriter::new(
+        File::create(output_path)
+            .wrap_err_with(|| format!("Failed to create {}", output_path.display()))?,
+    );
+    let header_bytes = header.encode_to_vec();
+    output.write_all(&(header_bytes.len() as u32).to_le_bytes())?;
+    output.write_all(&header_bytes)?;
+
+    let mut frames = 0;
+    let mut reader = JournalReader::open(journal)?;
+    while let Some((kind, payload)) = reader.next_record()? {
+        if kind == RECORD_FRAME {
+            output.write_all(&(payload.len() as u32).to_le_bytes())?;
+            output.write_all(&payload)?;
+            frames += 1;
+        }
+    }
+
+    output.flush()?;
+    output.get_ref().sync_all()?;
+    Ok(frames)
+}
+
+/// Turns a journal left behind by a crash into a KRec file at `output_path`.
+///
+/// Everything up to the last intact record is kept. Returns the number of
+/// recovered frames; the journal is removed on success.
+pub fn recover_journal(journal: impl AsRef<Path>, output_path: impl AsRef<Path>) -> Result<u64> {
+    let journal = journal.as_ref();
+    let frames = convert_journal(journal, output_path)?;
+    std::fs::remove_file(journal)?;
+    tracing::info!(
+        "Recovered {} frames from KRec journal {}",
+        frames,
+        journal.display()
+    );
+    Ok(frames)
+}
+
+/// Reads just the header of a finished KRec file.
+pub fn read_header(path: impl AsRef<Path>) -> Result<KRecHeader> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+    );
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_RECORD_LEN {
+        eyre::bail!("{} has a corrupt header", path.display());
+    }
+
+    let mut header = vec![0u8; len];
+    reader.read_exact(&mut header)?;
+    Ok(KRecHeader::decode(header.as_slice())?)
+}
+
+/// Loads a KRec journal into memory, keeping everything up to the last
+/// intact record.
+pub fn read_journal(journal: impl AsRef<Path>) -> Result<KRec> {
+    let mut reader = JournalReader::open(journal)?;
+    let mut header = None;
+    let mut frames = Vec::new();
+    while let Some((kind, payload)) = reader.next_record()? {
+        match kind {
+            RECORD_HEADER => header = Some(KRecHeader::decode(payload.as_slice())?),
+            _ => frames.push(KRecFrame::decode(payload.as_slice())?),
+        }
+    }
+
+    let mut krec = KRec::new(header.ok_or_else(|| eyre::eyre!("KRec journal has no header"))?);
+    for frame in frames {
+        krec.add_frame(frame);
+    }
+    Ok(krec)
+}
@@ -3,6 +3,7 @@ use crate::hal::Actuator;
 use crate::kos_proto::actuator::actuator_service_server::ActuatorService;
 use crate::kos_proto::actuator::*;
 use crate::kos_proto::common::ActionResponse;
+use crate::metrics::Metrics;
 use crate::telemetry::Telemetry;
 use crate::telemetry_types::{ActuatorCommand, ActuatorState};
 use std::sync::Arc;
@@ -45,10 +46,7 @@ impl ActuatorService for ActuatorServiceImpl {
         if let Some(telemetry) = telemetry {
             telemetry.increment_inference_step();
 
-            if let Err(e) = telemetry
-                .publish("actuator/command", &telemetry_commands)
-                .await
-            {
+            if let Err(e) = telemetry.try_publish("actuator/command", &telemetry_commands) {
                 warn!("Failed to publish telemetry: {}", e);
             }
         }
@@ -95,10 +93,12 @@ impl ActuatorService for ActuatorServiceImpl {
             .await
             .map_err(|e| Status::internal(format!("Failed to get actuators state, {:?}", e)))?;
 
+        Metrics::global().record_actuator_states(&states);
+
         let telemetry_states: Vec<_> = states.iter().map(ActuatorState::from).collect();
         let telemetry = Telemetry::get().await;
         if let Some(telemetry) = telemetry {
-            if let Err(e) = telemetry.publish("actuator/state", &telemetry_states).await {
+            if let Err(e) = telemetry.try_publish("actuator/state", &telemetry_states) {
                 warn!("Failed to publish telemetry: {}", e);
             }
         }
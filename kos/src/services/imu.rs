@@ -3,6 +3,7 @@ use crate::hal::IMU;
 use crate::kos_proto::common::ActionResponse;
 use crate::kos_proto::imu::imu_service_server::ImuService;
 use crate::kos_proto::imu::*;
+use crate::metrics::Metrics;
 use crate::telemetry::Telemetry;
 use crate::telemetry_types::{EulerAngles, ImuValues, Quaternion};
 use std::sync::Arc;
@@ -31,12 +32,11 @@ impl ImuService for IMUServiceImpl {
             .await
             .map_err(|e| Status::internal(format!("Failed to get IMU values, {:?}", e)))?;
 
+        Metrics::global().record_imu_sample("values");
+
         let telemetry = Telemetry::get().await;
         if let Some(telemetry) = telemetry {
-            if let Err(e) = telemetry
-                .publish("imu/values", &ImuValues::from(&values))
-                .await
-            {
+            if let Err(e) = telemetry.try_publish("imu/values", &ImuValues::from(&values)) {
                 tracing::warn!("Failed to publish telemetry: {}", e);
             }
         }
@@ -111,12 +111,11 @@ impl ImuService for IMUServiceImpl {
             .await
             .map_err(|e| Status::internal(format!("Failed to get euler, {:?}", e)))?;
 
+        Metrics::global().record_imu_sample("euler");
+
         let telemetry = Telemetry::get().await;
         if let Some(telemetry) = telemetry {
-            if let Err(e) = telemetry
-                .publish("imu/euler", &EulerAngles::from(&euler))
-                .await
-            {
+            if let Err(e) = telemetry.try_publish("imu/euler", &EulerAngles::from(&euler)) {
                 tracing::warn!("Failed to publish telemetry: {}", e);
             }
         }
@@ -134,11 +133,11 @@ impl ImuService for IMUServiceImpl {
             .await
             .map_err(|e| Status::internal(format!("Failed to get quaternion, {:?}", e)))?;
 
+        Metrics::global().record_imu_sample("quaternion");
+
         let telemetry = Telemetry::get().await;
         if let Some(telemetry) = telemetry {
-            if let Err(e) = telemetry
-                .publish("imu/quaternion", &Quaternion::from(&quaternion))
-                .await
+            if let Err(e) = telemetry.try_publish("imu/quaternion", &Quaternion::from(&quaternion))
             {
                 tracing::warn!("Failed to publish telemetry: {}", e);
             }
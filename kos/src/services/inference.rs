@@ -1,18 +1,205 @@
+use crate::config::kos_data_dir;
 use crate::hal::Inference;
-use crate::kos_proto::common::ActionResponse;
+use crate::inference_session::InferenceSession;
+use crate::kos_proto::common::{ActionResponse, Error, ErrorCode};
 use crate::kos_proto::inference::inference_service_server::InferenceService;
 use crate::kos_proto::inference::*;
+use crate::kos_proto::telemetry::{ForwardEvent, ModelEvent};
+use crate::model_store::{ModelStore, ModelUploads, PartialUpload};
+use crate::telemetry::Telemetry;
+use crate::tensor::{into_raw, synthetic_input, validate_inputs};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
 use tonic::{Request, Response, Status};
 use tracing::trace;
+use uuid::Uuid;
 
 pub struct InferenceServiceImpl {
     inference: Arc<dyn Inference>,
+    uploads: ModelUploads,
+    /// Model info per UID, used to validate `Forward` requests. Entries are
+    /// dropped whenever the model is uploaded, loaded, unloaded or deleted.
+    model_infos: RwLock<HashMap<String, Arc<ModelInfo>>>,
+    sessions: RwLock<HashMap<String, Arc<Mutex<InferenceSession>>>>,
 }
 
 impl InferenceServiceImpl {
     pub fn new(inference: Arc<dyn Inference>) -> Self {
-        Self { inference }
+        Self {
+            inference,
+            uploads: ModelUploads::new(kos_data_dir().join("uploads")),
+            model_infos: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Caps the size of models received with `UploadModelStream`.
+    pub fn with_max_model_size(mut self, max_size: u64) -> Self {
+        self.uploads = self.uploads.with_max_size(max_size);
+        self
+    }
+
+    /// Returns the info of a model, asking the backend the first time.
+    /// `None` if the backend doesn't know the model, in which case
+    /// `forward` reports the error itself.
+    async fn model_info(&self, model_uid: &str) -> Result<Option<Arc<ModelInfo>>, Status> {
+        if let Some(info) = self.model_infos.read().await.get(model_uid) {
+            return Ok(Some(info.clone()));
+        }
+
+        let request = GetModelsInfoRequest {
+            filter: Some(get_models_info_request::Filter::ModelUids(ModelUids {
+                uids: vec![model_uid.to_string()],
+            })),
+        };
+        let response = self
+            .inference
+            .get_models_info(request)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to get models info: {:?}", e)))?;
+        let Some(info) = response
+            .models
+            .into_iter()
+            .find(|info| info.uid == model_uid)
+        else {
+            return Ok(None);
+        };
+
+        let info = Arc::new(info);
+        self.model_infos
+            .write()
+            .await
+            .insert(model_uid.to_string(), info.clone());
+        Ok(Some(info))
+    }
+
+    async fn forget_model_infos(&self, model_uids: &[String]) {
+        let mut model_infos = self.model_infos.write().await;
+        for model_uid in model_uids {
+            model_infos.remove(model_uid);
+        }
+    }
+
+    async fn session(&self, session_id: &str) -> Option<Arc<Mutex<InferenceSession>>> {
+        self.sessions.read().await.get(session_id).cloned()
+    }
+
+    async fn register_model(
+        &self,
+        model: Vec<u8>,
+        metadata: Option<ModelMetadata>,
+    ) -> Result<UploadModelResponse, Status> {
+        let result = self.inference.upload_model(model, metadata).await;
+        if let Ok(response) = &result {
+            self.forget_model_infos(std::slice::from_ref(&response.model_uid))
+                .await;
+        }
+
+        match &result {
+            Ok(response) => {
+                let error = response.error.as_ref().map(|e| e.message.clone());
+                publish_model_event("upload", vec![response.model_uid.clone()], error).await
+            }
+            Err(e) => publish_model_event("upload", vec![], Some(e.to_string())).await,
+        }
+
+        result.map_err(|e| Status::internal(format!("Failed to upload model: {:?}", e)))
+    }
+}
+
+async fn publish_model_event(event: &str, model_uids: Vec<String>, error: Option<String>) {
+    let event = ModelEvent {
+        event: event.to_string(),
+        model_uids,
+        success: error.is_none(),
+        error,
+    };
+    Telemetry::publish_event("inference/model", &event).await;
+}
+
+/// Open sessions are capped so clients that never close theirs can't grow
+/// the state held on the robot without bound.
+const MAX_SESSIONS: usize = 64;
+
+fn action_error(code: ErrorCode, message: String) -> ActionResponse {
+    ActionResponse {
+        success: false,
+        error: Some(Error {
+            code: code as i32,
+            message,
+        }),
+    }
+}
+
+/// Iterations run by `Benchmark` when the request doesn't say.
+const DEFAULT_BENCHMARK_ITERATIONS: u32 = 100;
+
+/// Upper bounds on what one `Benchmark` request may ask for.
+const MAX_BENCHMARK_ITERATIONS: u32 = 10_000;
+const MAX_BENCHMARK_WARMUP: u32 = 1_000;
+
+/// Timed passes stop once a benchmark has run this long; the response
+/// reports how many were done.
+const MAX_BENCHMARK_DURATION: Duration = Duration::from_secs(60);
+
+fn benchmark_error(message: String) -> BenchmarkResponse {
+    BenchmarkResponse {
+        error: Some(Error {
+            code: ErrorCode::InvalidArgument as i32,
+            message,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Response for a chunked upload that stopped short; the client resumes
+/// from `received_size`.
+fn incomplete_upload(received_size: u64, message: String) -> UploadModelResponse {
+    UploadModelResponse {
+        model_uid: String::new(),
+        error: Some(Error {
+            code: ErrorCode::InvalidArgument as i32,
+            message,
+        }),
+        received_size,
+    }
+}
+
+async fn receive_chunks(
+    upload: &mut PartialUpload<'_>,
+    stream: &mut tonic::Streaming<UploadModelStreamRequest>,
+    mut chunk: Vec<u8>,
+    total_size: u64,
+) -> Result<(), Status> {
+    loop {
+        if upload.received() + chunk.len() as u64 > total_size {
+            return Err(Status::invalid_argument(format!(
+                "Upload is larger than its total size of {} bytes",
+                total_size
+            )));
+        }
+        upload
+            .append(&chunk)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to write model chunk: {:?}", e)))?;
+
+        match stream.message().await? {
+            Some(request) if request.header.is_some() => {
+                return Err(Status::invalid_argument(
+                    "Only the first message may contain the upload header",
+                ))
+            }
+            Some(request) => chunk = request.data,
+            None => return Ok(()),
+        }
     }
 }
 
@@ -26,11 +213,104 @@ impl InferenceService for InferenceServiceImpl {
         let request = request.into_inner();
         let model_data = request.model;
         let metadata: Option<ModelMetadata> = request.metadata;
-        self.inference
-            .upload_model(model_data, metadata)
+        self.register_model(model_data, metadata)
             .await
             .map(Response::new)
-            .map_err(|e| Status::internal(format!("Failed to upload model: {:?}", e)))
+    }
+
+    async fn upload_model_stream(
+        &self,
+        request: Request<tonic::Streaming<UploadModelStreamRequest>>,
+    ) -> Result<Response<UploadModelResponse>, Status> {
+        trace!("upload_model_stream request received");
+        let mut stream = request.into_inner();
+
+        // Get the first message which must contain the header
+        let first_msg = stream
+            .message()
+            .await
+            .map_err(|e| Status::internal(format!("Failed to receive upload header: {:?}", e)))?
+            .ok_or_else(|| Status::invalid_argument("Empty upload stream"))?;
+        let header = first_msg.header.ok_or_else(|| {
+            Status::invalid_argument("First message must contain the upload header")
+        })?;
+        let sha256 = header.sha256.to_ascii_lowercase();
+        if !ModelStore::is_valid_uid(&sha256) {
+            return Err(Status::invalid_argument(
+                "sha256 must be a hex-encoded SHA-256 digest",
+            ));
+        }
+        if header.total_size > self.uploads.max_size() {
+            return Err(Status::invalid_argument(format!(
+                "Model is {} bytes, the maximum is {}",
+                header.total_size,
+                self.uploads.max_size()
+            )));
+        }
+
+        let received = self
+            .uploads
+            .received(&sha256)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to read upload state: {:?}", e)))?;
+        if header.offset > received {
+            return Ok(Response::new(incomplete_upload(
+                received,
+                format!(
+                    "Offset {} is past the {} bytes received",
+                    header.offset, received
+                ),
+            )));
+        }
+
+        let mut upload = self
+            .uploads
+            .resume(&sha256, header.offset)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to resume upload: {:?}", e)))?
+            .ok_or_else(|| {
+                Status::aborted(format!("Model {} is already being uploaded", sha256))
+            })?;
+        trace!(
+            "Receiving model {} from offset {} of {} bytes",
+            sha256,
+            header.offset,
+            header.total_size
+        );
+
+        // Keep whatever arrived, even if the stream broke, so it can resume.
+        let result =
+            receive_chunks(&mut upload, &mut stream, first_msg.data, header.total_size).await;
+        if let Err(e) = upload.flush().await {
+            tracing::warn!("Failed to flush partial upload {}: {:?}", sha256, e);
+        }
+        result?;
+
+        if upload.received() < header.total_size {
+            return Ok(Response::new(incomplete_upload(
+                upload.received(),
+                format!(
+                    "Upload incomplete, received {} of {} bytes",
+                    upload.received(),
+                    header.total_size
+                ),
+            )));
+        }
+
+        let model = upload
+            .finish()
+            .await
+            .map_err(|e| Status::internal(format!("Failed to read uploaded model: {:?}", e)))?;
+        if ModelStore::model_uid(&model) != sha256 {
+            return Err(Status::invalid_argument(format!(
+                "Uploaded model does not match SHA-256 {}",
+                sha256
+            )));
+        }
+
+        let mut response = self.register_model(model, header.metadata).await?;
+        response.received_size = header.total_size;
+        Ok(Response::new(response))
     }
 
     async fn load_models(
@@ -39,9 +319,20 @@ impl InferenceService for InferenceServiceImpl {
     ) -> Result<Response<LoadModelsResponse>, Status> {
         trace!("load_models request received");
         let request = request.into_inner();
-        self.inference
-            .load_models(request.uids)
-            .await
+        let result = self.inference.load_models(request.uids.clone()).await;
+        self.forget_model_infos(&request.uids).await;
+
+        let error = match &result {
+            Ok(response) => response
+                .result
+                .as_ref()
+                .and_then(|result| result.error.as_ref())
+                .map(|e| e.message.clone()),
+            Err(e) => Some(e.to_string()),
+        };
+        publish_model_event("load", request.uids, error).await;
+
+        result
             .map(Response::new)
             .map_err(|e| Status::internal(format!("Failed to load models: {:?}", e)))
     }
@@ -52,13 +343,52 @@ impl InferenceService for InferenceServiceImpl {
     ) -> Result<Response<ActionResponse>, Status> {
         trace!("unload_models request received");
         let request = request.into_inner();
-        self.inference
-            .unload_models(request.uids)
-            .await
+        let result = self.inference.unload_models(request.uids.clone()).await;
+        self.forget_model_infos(&request.uids).await;
+
+        let error = match &result {
+            Ok(response) => response.error.as_ref().map(|e| e.message.clone()),
+            Err(e) => Some(e.to_string()),
+        };
+        publish_model_event("unload", request.uids, error).await;
+
+        result
             .map(Response::new)
             .map_err(|e| Status::internal(format!("Failed to unload models: {:?}", e)))
     }
 
+    async fn delete_model(
+        &self,
+        request: Request<DeleteModelRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        trace!("delete_model request received");
+        let request = request.into_inner();
+        let result = self.inference.delete_model(request.model_uid.clone()).await;
+        self.forget_model_infos(std::slice::from_ref(&request.model_uid))
+            .await;
+        let mut sessions = self.sessions.write().await;
+        let mut closed = vec![];
+        for (session_id, session) in sessions.iter() {
+            if session.lock().await.model_uid == request.model_uid {
+                closed.push(session_id.clone());
+            }
+        }
+        for session_id in closed {
+            sessions.remove(&session_id);
+        }
+        drop(sessions);
+
+        let error = match &result {
+            Ok(response) => response.error.as_ref().map(|e| e.message.clone()),
+            Err(e) => Some(e.to_string()),
+        };
+        publish_model_event("delete", vec![request.model_uid], error).await;
+
+        result
+            .map(Response::new)
+            .map_err(|e| Status::internal(format!("Failed to delete model: {:?}", e)))
+    }
+
     async fn get_models_info(
         &self,
         request: Request<GetModelsInfoRequest>,
@@ -78,11 +408,264 @@ impl InferenceService for InferenceServiceImpl {
     ) -> Result<Response<ForwardResponse>, Status> {
         trace!("forward request received");
         let request = request.into_inner();
+        let mut model_uid = request.model_uid;
+        let mut inputs = request.inputs;
 
-        self.inference
-            .forward(request.model_uid, request.inputs)
+        let session = match request.session_id.as_str() {
+            "" => None,
+            session_id => Some(self.session(session_id).await.ok_or_else(|| {
+                Status::invalid_argument(format!("Unknown session {}", session_id))
+            })?),
+        };
+        // Held until the state is updated, so steps of one session run in order.
+        let mut session = match &session {
+            Some(session) => Some(session.lock().await),
+            None => None,
+        };
+        if let Some(session) = &session {
+            if model_uid.is_empty() {
+                model_uid = session.model_uid.clone();
+            } else if model_uid != session.model_uid {
+                return Err(Status::invalid_argument(format!(
+                    "Session {} runs model {}, not {}",
+                    request.session_id, session.model_uid, model_uid
+                )));
+            }
+            session
+                .feed(&mut inputs)
+                .map_err(Status::invalid_argument)?;
+        }
+
+        if let Some(info) = self.model_info(&model_uid).await? {
+            if let Err(message) = validate_inputs(&info.input_specs, &inputs) {
+                let event = ForwardEvent {
+                    model_uid,
+                    latency_ms: 0.0,
+                    success: false,
+                    error: Some(message.clone()),
+                };
+                Telemetry::publish_event("inference/forward", &event).await;
+                return Err(Status::invalid_argument(message));
+            }
+        }
+
+        let start = Instant::now();
+        let result = self.inference.forward(model_uid.clone(), inputs).await;
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let error = match &result {
+            Ok(response) => response.error.as_ref().map(|e| e.message.clone()),
+            Err(e) => Some(e.to_string()),
+        };
+        let event = ForwardEvent {
+            model_uid,
+            latency_ms,
+            success: error.is_none(),
+            error,
+        };
+        Telemetry::publish_event("inference/forward", &event).await;
+
+        let mut response =
+            result.map_err(|e| Status::internal(format!("Failed to run inference: {:?}", e)))?;
+        if let Some(session) = session.as_mut() {
+            if response.error.is_none() {
+                session
+                    .update(&mut response.outputs)
+                    .map_err(Status::internal)?;
+            }
+        }
+        if request.raw_outputs {
+            response.outputs = response
+                .outputs
+                .into_iter()
+                .map(|(name, tensor)| (name, into_raw(tensor)))
+                .collect();
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn create_session(
+        &self,
+        request: Request<CreateSessionRequest>,
+    ) -> Result<Response<CreateSessionResponse>, Status> {
+        trace!("create_session request received");
+        let request = request.into_inner();
+        let error = |code: ErrorCode, message: String| {
+            Response::new(CreateSessionResponse {
+                session_id: String::new(),
+                error: Some(Error {
+                    code: code as i32,
+                    message,
+                }),
+            })
+        };
+
+        let Some(info) = self.model_info(&request.model_uid).await? else {
+            return Ok(error(
+                ErrorCode::InvalidArgument,
+                format!("Unknown model UID {}", request.model_uid),
+            ));
+        };
+        let session = match InferenceSession::new(&info, request.state_tensors, &request.dim_sizes)
+        {
+            Ok(session) => session,
+            Err(message) => return Ok(error(ErrorCode::InvalidArgument, message)),
+        };
+
+        let mut sessions = self.sessions.write().await;
+        if sessions.len() >= MAX_SESSIONS {
+            return Ok(error(
+                ErrorCode::ResourceExhausted,
+                format!("Too many open sessions (at most {})", MAX_SESSIONS),
+            ));
+        }
+        let session_id = Uuid::new_v4().to_string();
+        sessions.insert(session_id.clone(), Arc::new(Mutex::new(session)));
+        tracing::info!(
+            "Created session {} for model {}",
+            session_id,
+            request.model_uid
+        );
+
+        Ok(Response::new(CreateSessionResponse {
+            session_id,
+            error: None,
+        }))
+    }
+
+    async fn reset_session(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        trace!("reset_session request received");
+        let request = request.into_inner();
+        let Some(session) = self.session(&request.session_id).await else {
+            return Ok(Response::new(action_error(
+                ErrorCode::InvalidArgument,
+                format!("Unknown session {}", request.session_id),
+            )));
+        };
+        session.lock().await.reset();
+
+        Ok(Response::new(ActionResponse {
+            success: true,
+            error: None,
+        }))
+    }
+
+    async fn close_session(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        trace!("close_session request received");
+        let request = request.into_inner();
+        if self
+            .sessions
+            .write()
             .await
-            .map(Response::new)
-            .map_err(|e| Status::internal(format!("Failed to run inference: {:?}", e)))
+            .remove(&request.session_id)
+            .is_none()
+        {
+            return Ok(Response::new(action_error(
+                ErrorCode::InvalidArgument,
+                format!("Unknown session {}", request.session_id),
+            )));
+        }
+
+        Ok(Response::new(ActionResponse {
+            success: true,
+            error: None,
+        }))
+    }
+
+    async fn benchmark(
+        &self,
+        request: Request<BenchmarkRequest>,
+    ) -> Result<Response<BenchmarkResponse>, Status> {
+        trace!("benchmark request received");
+        let request = request.into_inner();
+        let iterations = match request.iterations {
+            0 => DEFAULT_BENCHMARK_ITERATIONS,
+            n => n,
+        };
+        if iterations > MAX_BENCHMARK_ITERATIONS {
+            return Ok(Response::new(benchmark_error(format!(
+                "At most {} iterations can be run, got {}",
+                MAX_BENCHMARK_ITERATIONS, iterations
+            ))));
+        }
+        if request.warmup > MAX_BENCHMARK_WARMUP {
+            return Ok(Response::new(benchmark_error(format!(
+                "At most {} warmup passes can be run, got {}",
+                MAX_BENCHMARK_WARMUP, request.warmup
+            ))));
+        }
+
+        let Some(info) = self.model_info(&request.model_uid).await? else {
+            return Ok(Response::new(benchmark_error(format!(
+                "Unknown model UID {}",
+                request.model_uid
+            ))));
+        };
+        let inputs: HashMap<String, Tensor> = info
+            .input_specs
+            .iter()
+            .map(|(name, spec)| (name.clone(), synthetic_input(spec, &request.dim_sizes)))
+            .collect();
+
+        // Calls the backend directly: the inputs are valid by construction,
+        // and per-pass forward events would drown out real traffic.
+        let mut latencies = Vec::new();
+        let start = Instant::now();
+        for i in 0..request.warmup + iterations {
+            if !latencies.is_empty() && start.elapsed() >= MAX_BENCHMARK_DURATION {
+                tracing::warn!(
+                    "Benchmark of model {} stopped after {} of {} iterations",
+                    request.model_uid,
+                    latencies.len(),
+                    iterations
+                );
+                break;
+            }
+            let pass_start = Instant::now();
+            let response = self
+                .inference
+                .forward(request.model_uid.clone(), inputs.clone())
+                .await
+                .map_err(|e| Status::internal(format!("Failed to run inference: {:?}", e)))?;
+            if let Some(error) = response.error {
+                return Ok(Response::new(benchmark_error(format!(
+                    "Forward pass {} failed: {}",
+                    i + 1,
+                    error.message
+                ))));
+            }
+            if i >= request.warmup {
+                latencies.push(pass_start.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+        let total_s = start.elapsed().as_secs_f64();
+        let timed_s = latencies.iter().sum::<f64>() / 1000.0;
+
+        latencies.sort_by(|a, b| a.total_cmp(b));
+        let response = BenchmarkResponse {
+            iterations: latencies.len() as u32,
+            min_ms: latencies[0],
+            mean_ms: latencies.iter().sum::<f64>() / latencies.len() as f64,
+            p50_ms: percentile(&latencies, 0.5),
+            p99_ms: percentile(&latencies, 0.99),
+            max_ms: latencies[latencies.len() - 1],
+            throughput_hz: latencies.len() as f64 / timed_s,
+            error: None,
+        };
+        tracing::info!(
+            "Benchmarked model {} in {:.2}s: mean {:.3}ms, p99 {:.3}ms, {:.1} Hz",
+            request.model_uid,
+            total_s,
+            response.mean_ms,
+            response.p99_ms,
+            response.throughput_hz
+        );
+        Ok(Response::new(response))
     }
 }
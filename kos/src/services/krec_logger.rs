@@ -1,57 +1,31 @@
-use crate::kos_proto::imu::{ImuValuesResponse, QuaternionResponse};
-use eyre::Result;
-use krec::{
-    ActuatorCommand, ActuatorState, ImuQuaternion, ImuValues, KRec, KRecFrame, KRecHeader, Vec3,
+use crate::recording::{
+    command_fields, events_path, fields_path, metadata_path, EventLog, FieldLog, KRecWriter,
+    RecordingMetadata, RecordingSources, WriterOptions,
 };
-use prost::Message;
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
-use serde::Deserialize;
+use crate::telemetry::{Telemetry, TelemetryRecord};
+use crate::telemetry_types;
+use crate::time_sync;
+use eyre::Result;
+use krec::{ActuatorCommand, ActuatorState, ImuQuaternion, ImuValues, KRecFrame, KRecHeader, Vec3};
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
-#[derive(Deserialize, Debug)]
-struct ActuatorCommandData {
-    frame_number: u64,
-    video_timestamp: u64,
-    inference_step: u64,
-    data: Vec<ActuatorCommandItem>,
-}
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
 
-#[derive(Deserialize, Debug)]
-struct ActuatorCommandItem {
-    actuator_id: u32,
-    position: Option<f64>,
-    velocity: Option<f64>,
-    torque: Option<f64>,
-}
-
-#[derive(Deserialize, Debug)]
-struct ActuatorStateData {
-    actuator_id: u32,
-    online: bool,
-    position: Option<f64>,
-    velocity: Option<f64>,
-    torque: Option<f64>,
-    temperature: Option<f64>,
-    voltage: Option<f32>,
-    current: Option<f32>,
-}
+/// Samples waiting for the journal thread before the logger task waits.
+const JOURNAL_CHANNEL_CAPACITY: usize = 4096;
 
-#[derive(Deserialize, Debug)]
-#[allow(unused)]
-struct ActuatorStateList {
-    frame_number: u64,
-    video_timestamp: u64,
-    inference_step: u64,
-    data: Vec<ActuatorStateData>,
+/// The subscriber task forwards samples to a dedicated thread that owns the
+/// journal, so file writes and syncs never block a runtime worker.
+struct Running {
+    task: JoinHandle<()>,
+    stop: oneshot::Sender<()>,
+    journal: std::thread::JoinHandle<KRecWriter>,
 }
 
 pub struct TelemetryLogger {
-    krec: Arc<Mutex<KRec>>,
-    _mqtt_client: AsyncClient,
-    current_inference_step: Arc<Mutex<u64>>,
-    current_frame: Arc<Mutex<KRecFrame>>,
+    running: Mutex<Option<Running>>,
+    header: KRecHeader,
     output_path: String,
 }
 
@@ -62,205 +36,292 @@ impl TelemetryLogger {
         output_path: impl AsRef<Path>,
         robot_name: String,
         robot_serial: String,
+        sources: RecordingSources,
     ) -> Result<Self> {
-        // Setup MQTT client
-        let mut mqtt_options = MqttOptions::new("kos-telemetry-logger", "localhost", 1883);
-        mqtt_options.set_keep_alive(std::time::Duration::from_secs(5));
-        let (mqtt_client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+        let telemetry = Telemetry::get()
+            .await
+            .ok_or_else(|| eyre::eyre!("Telemetry is not initialized or is disabled"))?;
+        let mut receiver = telemetry.subscribe();
+
+        let output_path = output_path
+            .as_ref()
+            .to_str()
+            .ok_or_else(|| eyre::eyre!("Failed to convert output path to string"))?
+            .to_owned();
+
+        // Describe the robot so the recording is self-contained
+        let metadata = RecordingMetadata::collect(&sources, &robot_name, &robot_serial).await;
 
         // Create KRec instance with header
         let header = KRecHeader {
             uuid,
             task: action,
-            robot_platform: robot_name.clone(),
-            robot_serial: robot_serial.clone(),
-            start_timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_nanos() as u64,
+            robot_platform: robot_name,
+            robot_serial,
+            start_timestamp: telemetry.time_sync().now().wall_ns,
             end_timestamp: 0,
-            actuator_configs: vec![],
+            actuator_configs: metadata.actuator_configs(),
         };
-        let krec = Arc::new(Mutex::new(KRec::new(header)));
 
-        let current_inference_step = Arc::new(Mutex::new(0));
-        let current_frame = Arc::new(Mutex::new(KRecFrame::default()));
+        let writer = KRecWriter::create(&output_path, &header, WriterOptions::default())?;
+        metadata.save(metadata_path(&output_path))?;
+        let event_log = EventLog::create(events_path(&output_path))?;
+        let field_log = FieldLog::create(fields_path(&output_path))?;
 
-        mqtt_client
-            .subscribe(
-                format!("robots/{}-{}/imu/values", robot_name, robot_serial),
-                QoS::AtLeastOnce,
-            )
-            .await?;
-        mqtt_client
-            .subscribe(
-                format!("robots/{}-{}/actuator/state", robot_name, robot_serial),
-                QoS::AtLeastOnce,
-            )
-            .await?;
-        mqtt_client
-            .subscribe(
-                format!("robots/{}-{}/actuator/command", robot_name, robot_serial),
-                QoS::AtLeastOnce,
-            )
-            .await?;
+        let (tx, rx) = mpsc::channel(JOURNAL_CHANNEL_CAPACITY);
+        let journal = std::thread::Builder::new()
+            .name("krec-journal".to_string())
+            .spawn(move || write_journal(rx, writer, event_log, field_log))?;
 
-        let output_path = output_path
-            .as_ref()
-            .to_str()
-            .ok_or_else(|| eyre::eyre!("Failed to convert output path to string"))?;
+        // Start processing telemetry samples
+        let (stop, mut stop_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let record = tokio::select! {
+                    biased;
+                    _ = &mut stop_rx => break,
+                    record = receiver.recv() => record,
+                };
+                let record = match record {
+                    Ok(record) => record,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("KRec logger lagged, skipped {} samples", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if tx.send(record).await.is_err() {
+                    return;
+                }
+            }
 
-        let logger = Self {
-            krec,
-            _mqtt_client: mqtt_client,
-            current_inference_step,
-            current_frame,
-            output_path: output_path.to_owned(),
-        };
+            // Keep the samples published before the stop.
+            loop {
+                let record = match receiver.try_recv() {
+                    Ok(record) => record,
+                    Err(TryRecvError::Lagged(skipped)) => {
+                        tracing::warn!("KRec logger lagged, skipped {} samples", skipped);
+                        continue;
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                };
+                if tx.send(record).await.is_err() {
+                    break;
+                }
+            }
+        });
 
-        // Start processing MQTT messages
-        let krec_clone = logger.krec.clone();
-        let current_step = logger.current_inference_step.clone();
-        let current_frame = logger.current_frame.clone();
-        let output_path = output_path.to_owned();
+        Ok(Self {
+            running: Mutex::new(Some(Running {
+                task,
+                stop,
+                journal,
+            })),
+            header,
+            output_path,
+        })
+    }
 
-        tokio::spawn(async move {
-            while let Ok(event) = eventloop.poll().await {
-                if let Event::Incoming(Packet::Publish(publish)) = event {
-                    let payload = &publish.payload;
-                    let topic = publish.topic;
-                    let mut frame = current_frame.lock().await;
+    pub async fn stop(&self) -> Result<()> {
+        let running = self
+            .running
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| eyre::eyre!("Telemetry logger is already stopped"))?;
 
-                    if topic.contains("/imu/values") {
-                        if let Ok(imu_values) = ImuValuesResponse::decode(payload.as_ref()) {
-                            // Convert IMUValuesResponse to ImuValues
-                            frame.imu_values = Some(ImuValues {
-                                accel: Some(Vec3 {
-                                    x: imu_values.accel_x,
-                                    y: imu_values.accel_y,
-                                    z: imu_values.accel_z,
-                                }),
-                                gyro: Some(Vec3 {
-                                    x: imu_values.gyro_x,
-                                    y: imu_values.gyro_y,
-                                    z: imu_values.gyro_z,
-                                }),
-                                mag: if imu_values.mag_x.is_some() {
-                                    Some(Vec3 {
-                                        x: imu_values.mag_x.unwrap_or_default(),
-                                        y: imu_values.mag_y.unwrap_or_default(),
-                                        z: imu_values.mag_z.unwrap_or_default(),
-                                    })
-                                } else {
-                                    None
-                                },
-                                quaternion: None,
-                            });
-                        } else {
-                            tracing::error!("Failed to decode ImuValuesResponse {:?}", payload);
-                        }
-                    } else if topic.contains("/imu/quaternion") {
-                        if let Ok(quat) = QuaternionResponse::decode(payload.as_ref()) {
-                            // Update quaternion in the current IMU values
-                            if frame.imu_values.is_none() {
-                                frame.imu_values = Some(ImuValues::default());
-                            }
-                            if let Some(ref mut imu_values) = frame.imu_values {
-                                imu_values.quaternion = Some(ImuQuaternion {
-                                    x: quat.x,
-                                    y: quat.y,
-                                    z: quat.z,
-                                    w: quat.w,
-                                });
-                            }
-                        } else {
-                            tracing::error!("Failed to decode QuaternionResponse {:?}", payload);
-                        }
-                    } else if topic.contains("/actuator/state") {
-                        match serde_json::from_slice::<ActuatorStateList>(payload) {
-                            Ok(state_list) => {
-                                for state in state_list.data {
-                                    frame.actuator_states.push(ActuatorState {
-                                        actuator_id: state.actuator_id,
-                                        online: state.online,
-                                        position: state.position,
-                                        velocity: state.velocity,
-                                        torque: state.torque,
-                                        temperature: state.temperature,
-                                        voltage: state.voltage,
-                                        current: state.current,
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to parse actuator state JSON: {:?}", e);
-                            }
-                        }
-                    } else if topic.contains("/actuator/command") {
-                        match serde_json::from_slice::<ActuatorCommandData>(payload) {
-                            Ok(command_data) => {
-                                frame.inference_step = command_data.inference_step;
-                                frame.video_timestamp = command_data.video_timestamp;
-                                frame.video_frame_number = command_data.frame_number;
-                                frame.real_timestamp = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap_or_default()
-                                    .as_nanos()
-                                    as u64;
+        // The task forwards what is already queued and exits. Dropping its
+        // sender lets the journal thread drain and hand back the writer.
+        let _ = running.stop.send(());
+        if let Err(e) = running.task.await {
+            tracing::warn!("KRec logger task failed: {}", e);
+        }
 
-                                for item in command_data.data {
-                                    frame.actuator_commands.push(ActuatorCommand {
-                                        actuator_id: item.actuator_id,
-                                        position: item.position.unwrap_or_default() as f32,
-                                        velocity: item.velocity.unwrap_or_default() as f32,
-                                        torque: item.torque.unwrap_or_default() as f32,
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to parse actuator command JSON: {:?}", e);
-                            }
-                        }
-                    }
+        // Update end timestamp
+        let mut header = self.header.clone();
+        header.end_timestamp = time_sync::wall_ns();
+
+        // Convert the journal into the final KRec file
+        let output_path = self.output_path.clone();
+        let journal = running.journal;
+        let frames = tokio::task::spawn_blocking(move || {
+            let writer = journal
+                .join()
+                .map_err(|_| eyre::eyre!("KRec journal thread panicked"))?;
+            writer.finish(&header, &output_path)
+        })
+        .await??;
+        tracing::info!("Saved final KRec file with {} frames", frames);
+
+        Ok(())
+    }
+}
 
-                    // Check if inference step has increased
-                    let mut current = current_step.lock().await;
-                    if frame.inference_step > *current {
-                        // Add frame to KRec
-                        let mut krec = krec_clone.lock().await;
+/// Runs on the journal thread: logs events and appends a frame to the
+/// journal each time the inference step advances, along with the fields its
+/// commands set. Returns the writer once the logger task goes away.
+fn write_journal(
+    mut rx: mpsc::Receiver<TelemetryRecord>,
+    mut writer: KRecWriter,
+    mut event_log: EventLog,
+    field_log: FieldLog,
+) -> KRecWriter {
+    let mut current_step = 0;
+    let mut frame = KRecFrame::default();
+    let mut fields = Vec::new();
+    // The field log has one entry per journal frame. After a failed write
+    // it stops, leaving the remaining frames' fields unknown.
+    let mut field_log = Some(field_log);
 
-                        krec.add_frame(frame.clone());
+    while let Some(record) = rx.blocking_recv() {
+        // Sensor samples arrive continuously, so this keeps the event log
+        // at most about a flush interval behind.
+        if let Err(e) = event_log.flush_if_due() {
+            tracing::warn!("Failed to flush event log: {}", e);
+        }
+        match event_log.append(&record) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                tracing::warn!("Failed to log {} event: {}", record.topic, e);
+                continue;
+            }
+        }
 
-                        // Save every 500 frames
-                        if krec.frames.len() % 500 == 0 {
-                            if let Err(e) = krec.save(&output_path) {
-                                tracing::warn!("Failed to save KRec file: {}", e);
-                            } else {
-                                tracing::debug!("Saved {} frames to KRec file", krec.frames.len());
-                            }
+        apply_record(&mut frame, &mut fields, &record);
+
+        // Check if inference step has increased
+        if frame.inference_step > current_step {
+            // Append frame to the journal
+            match writer.write_frame(&frame) {
+                Ok(()) => {
+                    if let Some(log) = &mut field_log {
+                        if let Err(e) = log.append(&fields) {
+                            tracing::warn!("Failed to log command fields: {}", e);
+                            field_log = None;
                         }
-                        // Reset frame for next step
-                        *frame = KRecFrame::default();
-                        *current = frame.inference_step;
                     }
                 }
+                Err(e) => tracing::warn!("Failed to write KRec frame: {}", e),
             }
-        });
-
-        Ok(logger)
+            // Reset frame for next step
+            current_step = frame.inference_step;
+            frame = KRecFrame::default();
+            fields.clear();
+        }
     }
 
-    pub async fn stop(&self) -> Result<()> {
-        let mut krec = self.krec.lock().await;
-
-        // Update end timestamp
-        krec.header.end_timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_nanos() as u64;
+    if let Err(e) = event_log.flush() {
+        tracing::warn!("Failed to flush event log: {}", e);
+    }
+    if let Some(mut log) = field_log {
+        if let Err(e) = log.flush() {
+            tracing::warn!("Failed to log command fields: {}", e);
+        }
+    }
+    writer
+}
 
-        // Save final state
-        krec.save(&self.output_path)?;
-        tracing::info!("Saved final KRec file with {} frames", krec.frames.len());
+/// Folds a single telemetry sample into the frame currently being assembled.
+/// `fields` gets the field mask of each command added to the frame.
+fn apply_record(frame: &mut KRecFrame, fields: &mut Vec<u8>, record: &TelemetryRecord) {
+    match record.topic.as_str() {
+        "imu/values" => match record.decode::<telemetry_types::ImuValues>() {
+            Ok(imu_values) => {
+                let imu_values = imu_values.data;
+                let quaternion = frame.imu_values.as_ref().and_then(|imu| imu.quaternion);
+                frame.imu_values = Some(ImuValues {
+                    accel: Some(Vec3 {
+                        x: imu_values.accel_x,
+                        y: imu_values.accel_y,
+                        z: imu_values.accel_z,
+                    }),
+                    gyro: Some(Vec3 {
+                        x: imu_values.gyro_x,
+                        y: imu_values.gyro_y,
+                        z: imu_values.gyro_z,
+                    }),
+                    mag: if imu_values.mag_x.is_some() {
+                        Some(Vec3 {
+                            x: imu_values.mag_x.unwrap_or_default(),
+                            y: imu_values.mag_y.unwrap_or_default(),
+                            z: imu_values.mag_z.unwrap_or_default(),
+                        })
+                    } else {
+                        None
+                    },
+                    quaternion,
+                });
+            }
+            Err(e) => {
+                tracing::error!("Failed to decode IMU values: {:?}", e);
+            }
+        },
+        "imu/quaternion" => {
+            match record.decode::<telemetry_types::Quaternion>() {
+                Ok(quat) => {
+                    // Update quaternion in the current IMU values
+                    let quat = quat.data;
+                    frame
+                        .imu_values
+                        .get_or_insert_with(ImuValues::default)
+                        .quaternion = Some(ImuQuaternion {
+                        x: quat.x,
+                        y: quat.y,
+                        z: quat.z,
+                        w: quat.w,
+                    });
+                }
+                Err(e) => {
+                    tracing::error!("Failed to decode quaternion: {:?}", e);
+                }
+            }
+        }
+        "actuator/state" => match record.decode::<Vec<telemetry_types::ActuatorState>>() {
+            Ok(state_list) => {
+                for state in state_list.data {
+                    frame.actuator_states.push(ActuatorState {
+                        actuator_id: state.actuator_id,
+                        online: state.online,
+                        position: state.position,
+                        velocity: state.velocity,
+                        torque: state.torque,
+                        temperature: state.temperature,
+                        voltage: state.voltage,
+                        current: state.current,
+                    });
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to decode actuator state: {:?}", e);
+            }
+        },
+        "actuator/command" => match record.decode::<Vec<telemetry_types::ActuatorCommand>>() {
+            Ok(command_data) => {
+                frame.inference_step = command_data.inference_step;
+                frame.video_timestamp = command_data.video_timestamp;
+                frame.video_frame_number = command_data.frame_number;
+                // Stamp the frame with the time the command was issued
+                // rather than when it reached the logger.
+                frame.real_timestamp = match command_data.wall_ns {
+                    0 => time_sync::wall_ns(),
+                    wall_ns => wall_ns,
+                };
 
-        Ok(())
+                for item in command_data.data {
+                    fields.push(command_fields(&item));
+                    frame.actuator_commands.push(ActuatorCommand {
+                        actuator_id: item.actuator_id,
+                        position: item.position.unwrap_or_default() as f32,
+                        velocity: item.velocity.unwrap_or_default() as f32,
+                        torque: item.torque.unwrap_or_default() as f32,
+                    });
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to decode actuator command: {:?}", e);
+            }
+        },
+        _ => {}
     }
 }
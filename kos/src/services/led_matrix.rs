@@ -2,6 +2,8 @@ use crate::hal::LEDMatrix;
 use crate::kos_proto::common::ActionResponse;
 use crate::kos_proto::led_matrix::led_matrix_service_server::LedMatrixService;
 use crate::kos_proto::led_matrix::*;
+use crate::kos_proto::telemetry::LedEvent;
+use crate::telemetry::Telemetry;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
 use tracing::trace;
@@ -16,6 +18,13 @@ impl LEDMatrixServiceImpl {
     }
 }
 
+fn action_error(result: &eyre::Result<ActionResponse>) -> Option<String> {
+    match result {
+        Ok(response) => response.error.as_ref().map(|e| e.message.clone()),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
 #[tonic::async_trait]
 impl LedMatrixService for LEDMatrixServiceImpl {
     async fn get_matrix_info(
@@ -37,12 +46,21 @@ impl LedMatrixService for LEDMatrixServiceImpl {
         request: Request<WriteBufferRequest>,
     ) -> Result<Response<ActionResponse>, Status> {
         let buffer = request.into_inner().buffer;
+        let buffer_bytes = buffer.len() as u64;
 
-        let response = self
-            .led_matrix
-            .write_buffer(buffer.clone())
-            .await
-            .map_err(|e| Status::internal(format!("Failed to write buffer, {:?}", e)))?;
+        let result = self.led_matrix.write_buffer(buffer.clone()).await;
+
+        let event = LedEvent {
+            kind: "buffer".to_string(),
+            buffer_bytes,
+            success: result.as_ref().is_ok_and(|response| response.success),
+            error: action_error(&result),
+            ..Default::default()
+        };
+        Telemetry::publish_event("led/write", &event).await;
+
+        let response =
+            result.map_err(|e| Status::internal(format!("Failed to write buffer, {:?}", e)))?;
 
         trace!("Writing LED buffer, buffer length: {}", buffer.len());
         Ok(Response::new(response))
@@ -54,7 +72,9 @@ impl LedMatrixService for LEDMatrixServiceImpl {
     ) -> Result<Response<ActionResponse>, Status> {
         let request = request.into_inner();
 
-        let response = self
+        let buffer_bytes = request.buffer.len() as u64;
+
+        let result = self
             .led_matrix
             .write_color_buffer(
                 request.buffer,
@@ -63,7 +83,21 @@ impl LedMatrixService for LEDMatrixServiceImpl {
                 request.format.clone(),
                 request.brightness,
             )
-            .await
+            .await;
+
+        let event = LedEvent {
+            kind: "color_buffer".to_string(),
+            buffer_bytes,
+            width: request.width,
+            height: request.height,
+            format: request.format.clone(),
+            brightness: request.brightness,
+            success: result.as_ref().is_ok_and(|response| response.success),
+            error: action_error(&result),
+        };
+        Telemetry::publish_event("led/write", &event).await;
+
+        let response = result
             .map_err(|e| Status::internal(format!("Failed to write color buffer, {:?}", e)))?;
 
         trace!(
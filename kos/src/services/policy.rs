@@ -1,6 +1,8 @@
-use crate::hal::Policy;
+use crate::hal::{ActionResponse, Policy};
 use crate::kos_proto::policy::policy_service_server::PolicyService;
 use crate::kos_proto::policy::*;
+use crate::kos_proto::telemetry::PolicyEvent;
+use crate::telemetry::Telemetry;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
 use tracing::trace;
@@ -24,17 +26,38 @@ impl PolicyService for PolicyServiceImpl {
         trace!("Starting Policy");
         let req = request.get_ref();
 
-        Ok(Response::new(
-            self.policy
-                .start_policy(
-                    req.action.clone(),
-                    req.action_scale,
-                    req.episode_length,
-                    req.dry_run,
-                )
-                .await
-                .map_err(|e| Status::internal(format!("Failed to start policy: {:?}", e)))?,
-        ))
+        let result = self
+            .policy
+            .start_policy(
+                req.action.clone(),
+                req.action_scale,
+                req.episode_length,
+                req.dry_run,
+            )
+            .await;
+
+        let (policy_uuid, error) = match &result {
+            Ok(response) => (
+                response.policy_uuid.clone(),
+                response.error.as_ref().map(|e| e.message.clone()),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let event = PolicyEvent {
+            event: "start".to_string(),
+            policy_uuid,
+            action: req.action.clone(),
+            action_scale: req.action_scale,
+            episode_length: req.episode_length,
+            dry_run: req.dry_run,
+            success: error.is_none(),
+            error,
+        };
+        Telemetry::publish_event("policy/event", &event).await;
+
+        Ok(Response::new(result.map_err(|e| {
+            Status::internal(format!("Failed to start policy: {:?}", e))
+        })?))
     }
 
     async fn stop_policy(
@@ -43,9 +66,27 @@ impl PolicyService for PolicyServiceImpl {
     ) -> Result<Response<StopPolicyResponse>, Status> {
         trace!("Stopping Policy");
 
-        Ok(Response::new(self.policy.stop_policy().await.map_err(
-            |e| Status::internal(format!("Failed to stop policy: {:?}", e)),
-        )?))
+        let result = self.policy.stop_policy().await;
+
+        let (policy_uuid, error) = match &result {
+            Ok(response) => (
+                response.policy_uuid.clone(),
+                response.error.as_ref().map(|e| e.message.clone()),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let event = PolicyEvent {
+            event: "stop".to_string(),
+            policy_uuid,
+            success: error.is_none(),
+            error,
+            ..Default::default()
+        };
+        Telemetry::publish_event("policy/event", &event).await;
+
+        Ok(Response::new(result.map_err(|e| {
+            Status::internal(format!("Failed to stop policy: {:?}", e))
+        })?))
     }
 
     async fn get_state(&self, _request: Request<()>) -> Result<Response<GetStateResponse>, Status> {
@@ -55,4 +96,18 @@ impl PolicyService for PolicyServiceImpl {
             |e| Status::internal(format!("Failed to get policy state: {:?}", e)),
         )?))
     }
+
+    async fn set_policy_command(
+        &self,
+        request: Request<SetPolicyCommandRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        trace!("Setting Policy Command");
+
+        Ok(Response::new(
+            self.policy
+                .set_command(request.into_inner().commands)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to set policy command: {:?}", e)))?,
+        ))
+    }
 }
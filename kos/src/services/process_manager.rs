@@ -1,10 +1,17 @@
+use crate::grpc_interface::google::longrunning::Operation;
 use crate::hal::ProcessManager;
+use crate::kos_proto::common::ActionResponse;
 use crate::kos_proto::process_manager::process_manager_service_server::ProcessManagerService;
 use crate::kos_proto::process_manager::*;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
 use tracing::trace;
 
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
 pub struct ProcessManagerServiceImpl {
     process_manager: Arc<dyn ProcessManager>,
 }
@@ -44,4 +51,117 @@ impl ProcessManagerService for ProcessManagerServiceImpl {
                 .map_err(|e| Status::internal(format!("Failed to stop K-Clip, {:?}", e)))?,
         ))
     }
+
+    async fn list_clips(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ListClipsResponse>, Status> {
+        trace!("Listing clips");
+
+        Ok(Response::new(
+            self.process_manager
+                .list_clips()
+                .await
+                .map_err(|e| Status::internal(format!("Failed to list clips, {:?}", e)))?,
+        ))
+    }
+
+    type DownloadClipStream =
+        Pin<Box<dyn Stream<Item = Result<DownloadClipResponse, Status>> + Send>>;
+
+    async fn download_clip(
+        &self,
+        request: Request<DownloadClipRequest>,
+    ) -> Result<Response<Self::DownloadClipStream>, Status> {
+        let request = request.into_inner();
+        trace!("Downloading clip {}", request.clip_uuid);
+
+        let chunk_size = request
+            .chunk_size
+            .map(|size| size as usize)
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+            .clamp(1, MAX_CHUNK_SIZE);
+
+        let download = self
+            .process_manager
+            .download_clip(request.clip_uuid, chunk_size)
+            .await
+            .map_err(|e| Status::not_found(format!("Failed to download clip, {:?}", e)))?;
+
+        let total_size = download.total_size;
+        let mut chunks = download.stream;
+        let response_stream = async_stream::try_stream! {
+            let mut offset = 0;
+            while let Some(chunk) = chunks.next().await {
+                let data = chunk.map_err(|e| Status::internal(format!("Failed to read clip, {:?}", e)))?;
+                let len = data.len() as u64;
+                yield DownloadClipResponse {
+                    data: data.to_vec(),
+                    offset,
+                    total_size,
+                };
+                offset += len;
+            }
+        };
+
+        Ok(Response::new(Box::pin(response_stream)))
+    }
+
+    async fn delete_clip(
+        &self,
+        request: Request<DeleteClipRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        let clip_uuid = request.into_inner().clip_uuid;
+        trace!("Deleting clip {}", clip_uuid);
+
+        Ok(Response::new(
+            self.process_manager
+                .delete_clip(clip_uuid)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to delete clip, {:?}", e)))?,
+        ))
+    }
+
+    async fn annotate_clip(
+        &self,
+        request: Request<AnnotateClipRequest>,
+    ) -> Result<Response<ActionResponse>, Status> {
+        let request = request.into_inner();
+        trace!("Annotating clip {}", request.clip_uuid);
+
+        let annotation = request
+            .annotation
+            .ok_or_else(|| Status::invalid_argument("Annotation is required"))?;
+
+        Ok(Response::new(
+            self.process_manager
+                .annotate_clip(request.clip_uuid, annotation)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to annotate clip, {:?}", e)))?,
+        ))
+    }
+
+    async fn replay_clip(
+        &self,
+        request: Request<ReplayClipRequest>,
+    ) -> Result<Response<Operation>, Status> {
+        let request = request.into_inner();
+        trace!("Replaying clip, request: {:?}", request);
+
+        if request.source.is_none() {
+            return Err(Status::invalid_argument("A clip UUID or path is required"));
+        }
+        if let Some(speed) = request.speed {
+            if !(speed.is_finite() && speed > 0.0) {
+                return Err(Status::invalid_argument("Speed must be positive"));
+            }
+        }
+
+        Ok(Response::new(
+            self.process_manager
+                .replay_clip(request)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to replay clip, {:?}", e)))?,
+        ))
+    }
 }
@@ -2,6 +2,8 @@ use crate::hal::Sound;
 use crate::kos_proto::common::ActionResponse;
 use crate::kos_proto::sound::sound_service_server::SoundService;
 use crate::kos_proto::sound::*;
+use crate::kos_proto::telemetry::AudioEvent;
+use crate::telemetry::Telemetry;
 use bytes::Bytes;
 use futures::Stream;
 use std::pin::Pin;
@@ -20,6 +22,24 @@ impl SoundServiceImpl {
     }
 }
 
+fn audio_event(event: &str, config: &AudioConfig) -> AudioEvent {
+    AudioEvent {
+        event: event.to_string(),
+        sample_rate: config.sample_rate,
+        bit_depth: config.bit_depth,
+        channels: config.channels,
+        success: true,
+        ..Default::default()
+    }
+}
+
+fn action_error(result: &Result<ActionResponse, Status>) -> Option<String> {
+    match result {
+        Ok(response) => response.error.as_ref().map(|e| e.message.clone()),
+        Err(e) => Some(e.message().to_string()),
+    }
+}
+
 #[tonic::async_trait]
 impl SoundService for SoundServiceImpl {
     async fn get_audio_info(
@@ -56,16 +76,33 @@ impl SoundService for SoundServiceImpl {
         let (tx, _rx) = mpsc::channel(32);
 
         // Start playback with the sender
-        let response = self.sound.play_audio(config, tx.clone()).await?;
+        let result = self.sound.play_audio(config, tx.clone()).await;
+
+        let error = action_error(&result);
+        let event = AudioEvent {
+            success: error.is_none(),
+            error,
+            ..audio_event("play_start", &config)
+        };
+        Telemetry::publish_event("sound/event", &event).await;
+        let response = result?;
 
         // Spawn task to handle incoming audio data
         tokio::spawn(async move {
+            let mut bytes = 0;
             while let Ok(Some(msg)) = stream.message().await {
+                bytes += msg.audio_data.len() as u64;
                 if let Err(e) = tx.send(Bytes::from(msg.audio_data)).await {
                     tracing::error!("Failed to send audio data: {:?}", e);
                     break;
                 }
             }
+
+            let event = AudioEvent {
+                bytes,
+                ..audio_event("play_end", &config)
+            };
+            Telemetry::publish_event("sound/event", &event).await;
         });
 
         Ok(Response::new(response))
@@ -90,7 +127,17 @@ impl SoundService for SoundServiceImpl {
             .config
             .ok_or_else(|| Status::invalid_argument("Audio configuration is required"))?;
 
-        let stream = self.sound.record_audio(config, request.duration_ms).await?;
+        let result = self.sound.record_audio(config, request.duration_ms).await;
+
+        let error = result.as_ref().err().map(|e| e.message().to_string());
+        let event = AudioEvent {
+            duration_ms: request.duration_ms,
+            success: error.is_none(),
+            error,
+            ..audio_event("record_start", &config)
+        };
+        Telemetry::publish_event("sound/event", &event).await;
+        let stream = result?;
 
         // Convert the stream into the expected response type
         let response_stream = async_stream::try_stream! {
@@ -109,7 +156,17 @@ impl SoundService for SoundServiceImpl {
         &self,
         _request: Request<()>,
     ) -> Result<Response<ActionResponse>, Status> {
-        let response = self.sound.stop_recording().await?;
+        let result = self.sound.stop_recording().await;
+
+        let error = action_error(&result);
+        let event = AudioEvent {
+            event: "record_stop".to_string(),
+            success: error.is_none(),
+            error,
+            ..Default::default()
+        };
+        Telemetry::publish_event("sound/event", &event).await;
+        let response = result?;
         trace!("Stopping audio recording");
         Ok(Response::new(response))
     }
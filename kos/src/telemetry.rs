@@ -3,22 +3,384 @@
 // Mosquitto is the broker which will pass messages to InfluxDB
 // We log desired vs actual joint angles (torque/velocity/position if applicable),
 // as well as IMU data.
+//
+// Every sample is first published on an in-process broadcast bus, which the
+// KRec logger subscribes to directly. External sinks (MQTT, InfluxDB) sit on
+// top of that; they are rate limited per topic and never block the caller.
+// Samples MQTT can't send (broker down, queue full) go to a bounded on-disk
+// spool and are replayed once the connection comes back.
 
+use crate::telemetry_encoding::{TelemetryData, TelemetryEncoding};
+use crate::telemetry_influx::{InfluxConfig, InfluxSink};
+use crate::telemetry_spool::{Spool, SpoolConfig};
+use crate::time_sync::{TimeStamp, TimeSync};
+use async_trait::async_trait;
+use bytes::Bytes;
 use eyre::Result;
 use lazy_static::lazy_static;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use serde::Serialize;
-use std::sync::atomic::{AtomicU64, Ordering};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex};
+
+/// Number of samples buffered on the in-process bus before slow subscribers start lagging.
+const TELEMETRY_BUS_CAPACITY: usize = 1024;
+
+/// Topic key in `TelemetryConfig::rate_limits` that applies to every topic
+/// without its own entry.
+pub const DEFAULT_RATE_LIMIT_KEY: &str = "*";
 
 #[derive(Clone)]
 pub struct Telemetry {
-    client: Arc<AsyncClient>,
+    mqtt: Option<Arc<MqttSink>>,
+    influx: Option<Arc<InfluxSink>>,
+    sinks: Vec<Arc<dyn TelemetrySink>>,
+    rate_limiter: Arc<RateLimiter>,
+    counters: Arc<TelemetryCounters>,
+    bus: broadcast::Sender<TelemetryRecord>,
+    encoding: TelemetryEncoding,
     pub robot_id: String,
-    frame_number: Arc<Mutex<u64>>,
-    video_timestamp: Arc<Mutex<u64>>,
-    inference_step: Arc<AtomicU64>,
+    time_sync: Arc<TimeSync>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TelemetryConfig {
+    /// MQTT sink settings, or `None` to keep telemetry in-process.
+    pub mqtt: Option<MqttConfig>,
+    /// InfluxDB line-protocol sink, used alongside or instead of MQTT.
+    pub influx: Option<InfluxConfig>,
+    pub encoding: TelemetryEncoding,
+    /// Maximum publish rate to external sinks in Hz, keyed by topic (e.g.
+    /// `actuator/state`) or `DEFAULT_RATE_LIMIT_KEY`. The in-process bus is
+    /// never rate limited.
+    pub rate_limits: HashMap<String, f64>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            mqtt: Some(MqttConfig::default()),
+            influx: None,
+            encoding: TelemetryEncoding::default(),
+            rate_limits: HashMap::new(),
+        }
+    }
+}
+
+/// Connection settings for the optional MQTT sink.
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub qos: QoS,
+    /// Requests buffered for the MQTT event loop; samples beyond this are spooled.
+    pub queue_capacity: usize,
+    /// First delay before reconnecting; doubles up to `max_reconnect_delay`.
+    pub min_reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Where unsent samples are kept, or `None` to drop them.
+    pub spool: Option<SpoolConfig>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<MqttTlsConfig>,
+}
+
+/// PEM files for connecting to the broker over TLS.
+#[derive(Clone, Debug)]
+pub struct MqttTlsConfig {
+    pub ca_cert: PathBuf,
+    /// Client certificate and key, for brokers that require mutual TLS.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl MqttTlsConfig {
+    fn transport(&self) -> Result<Transport> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| eyre::eyre!("Failed to read {}: {}", path.display(), e))
+        };
+
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+            (None, None) => None,
+            _ => eyre::bail!("MQTT client certificate and key must be given together"),
+        };
+        Ok(Transport::tls(read(&self.ca_cert)?, client_auth, None))
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            qos: QoS::AtLeastOnce,
+            queue_capacity: 64,
+            min_reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+            spool: Some(SpoolConfig::default()),
+            username: None,
+            password: None,
+            tls: None,
+        }
+    }
+}
+
+/// Counters for samples handed to the MQTT sink.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TelemetryStats {
+    pub connected: bool,
+    pub published: u64,
+    /// Skipped because the topic was over its rate limit.
+    pub rate_limited: u64,
+    /// Written to the spool because they couldn't be sent.
+    pub spooled: u64,
+    /// Sent from the spool after reconnecting.
+    pub replayed: u64,
+    /// Lost because they couldn't be sent and the spool was full or disabled.
+    pub dropped: u64,
+    /// Bytes currently held in the spool.
+    pub spool_bytes: u64,
+    /// Lost to spool write errors after being queued for the spool.
+    pub spool_errors: u64,
+    /// Dropped because the InfluxDB writer fell behind.
+    pub influx_dropped: u64,
+    /// InfluxDB lines lost to failed writes.
+    pub influx_failed: u64,
+}
+
+#[derive(Default)]
+struct TelemetryCounters {
+    published: AtomicU64,
+    rate_limited: AtomicU64,
+    spooled: AtomicU64,
+    replayed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// A destination for telemetry samples outside the process.
+#[async_trait]
+pub trait TelemetrySink: Send + Sync {
+    /// Hands a sample to the sink. Called from RPC handlers, so it must not
+    /// block; sinks count or drop what they can't keep up with.
+    fn try_send(&self, record: &TelemetryRecord);
+
+    /// Flushes anything buffered before the daemon exits.
+    async fn shutdown(&self) {}
+}
+
+/// Retained payloads on `robots/<id>/status`. The broker publishes
+/// `STATUS_OFFLINE` as our last will if the connection drops uncleanly.
+pub const STATUS_ONLINE: &str = "online";
+pub const STATUS_OFFLINE: &str = "offline";
+
+struct MqttSink {
+    client: AsyncClient,
+    qos: QoS,
+    robot_id: String,
+    counters: Arc<TelemetryCounters>,
+    status_topic: String,
+    connected: AtomicBool,
+    spool: Option<Spool>,
+    replaying: AtomicBool,
+}
+
+impl MqttSink {
+    /// Replays the spool in the background unless a replay is already running.
+    fn start_replay(self: &Arc<Self>, counters: &Arc<TelemetryCounters>) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        if spool.bytes() == 0 || self.replaying.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let sink = self.clone();
+        let spool = spool.clone();
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            match spool.replay(&sink.client, sink.qos, &sink.connected).await {
+                Ok(0) => {}
+                Ok(replayed) => {
+                    counters.replayed.fetch_add(replayed, Ordering::Relaxed);
+                    tracing::info!("Replayed {} spooled telemetry samples", replayed);
+                }
+                Err(e) => tracing::warn!("Failed to replay spooled telemetry: {}", e),
+            }
+            sink.replaying.store(false, Ordering::Release);
+        });
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for MqttSink {
+    fn try_send(&self, record: &TelemetryRecord) {
+        let full_topic = format!(
+            "robots/{}/{}{}",
+            self.robot_id,
+            record.topic,
+            record.encoding.topic_suffix()
+        );
+
+        if self.connected.load(Ordering::Acquire) {
+            match self.client.try_publish(
+                full_topic.clone(),
+                self.qos,
+                false,
+                record.payload.clone(),
+            ) {
+                Ok(()) => {
+                    self.counters.published.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(e) => tracing::trace!("MQTT queue full on {}: {}", record.topic, e),
+            }
+        }
+
+        let spooled = self
+            .spool
+            .as_ref()
+            .is_some_and(|spool| spool.try_append(&full_topic, record.payload.clone()));
+        if spooled {
+            self.counters.spooled.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                tracing::warn!("Dropped {} telemetry samples so far", dropped);
+            }
+        }
+    }
+
+    /// Marks the robot offline and disconnects from the broker. A clean
+    /// disconnect suppresses the last will, so the status is published here.
+    async fn shutdown(&self) {
+        if !self.connected.load(Ordering::Acquire) {
+            return;
+        }
+
+        if let Err(e) = self
+            .client
+            .publish(&self.status_topic, QoS::AtLeastOnce, true, STATUS_OFFLINE)
+            .await
+        {
+            tracing::warn!("Failed to publish offline status: {}", e);
+        }
+        let _ = self.client.disconnect().await;
+        // Give the event loop a moment to flush both requests.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Polls the MQTT event loop forever, reconnecting with exponential backoff
+/// and replaying the spool whenever the connection is (re)established.
+async fn run_event_loop(
+    mut eventloop: EventLoop,
+    sink: Arc<MqttSink>,
+    counters: Arc<TelemetryCounters>,
+    min_delay: Duration,
+    max_delay: Duration,
+) {
+    let mut delay = min_delay;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                sink.connected.store(true, Ordering::Release);
+                if let Err(e) = sink.client.try_publish(
+                    sink.status_topic.clone(),
+                    QoS::AtLeastOnce,
+                    true,
+                    STATUS_ONLINE,
+                ) {
+                    tracing::warn!("Failed to publish online status: {}", e);
+                }
+                delay = min_delay;
+                sink.start_replay(&counters);
+            }
+            Ok(notification) => {
+                tracing::trace!("MQTT Event: {:?}", notification);
+                if sink.connected.load(Ordering::Acquire) {
+                    sink.start_replay(&counters);
+                }
+            }
+            Err(e) => {
+                if sink.connected.swap(false, Ordering::AcqRel) {
+                    tracing::warn!("Lost connection to MQTT broker: {}", e);
+                } else {
+                    tracing::debug!("MQTT connection failed: {}, retrying in {:?}", e, delay);
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(max_delay);
+            }
+        }
+    }
+}
+
+/// Enforces a minimum interval between external publishes on each topic.
+struct RateLimiter {
+    intervals: HashMap<String, Duration>,
+    default_interval: Option<Duration>,
+    last_sent: std::sync::Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    fn new(rate_limits: &HashMap<String, f64>) -> Self {
+        let interval = |hz: f64| Duration::from_secs_f64(1.0 / hz);
+        Self {
+            intervals: rate_limits
+                .iter()
+                .filter(|(topic, hz)| topic.as_str() != DEFAULT_RATE_LIMIT_KEY && **hz > 0.0)
+                .map(|(topic, hz)| (topic.clone(), interval(*hz)))
+                .collect(),
+            default_interval: rate_limits
+                .get(DEFAULT_RATE_LIMIT_KEY)
+                .filter(|hz| **hz > 0.0)
+                .map(|hz| interval(*hz)),
+            last_sent: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether `topic` may be published now, and if so records it.
+    fn allow(&self, topic: &str) -> bool {
+        let Some(interval) = self.intervals.get(topic).copied().or(self.default_interval) else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let mut last_sent = self.last_sent.lock().unwrap_or_else(|e| e.into_inner());
+        match last_sent.get_mut(topic) {
+            Some(last) if now.duration_since(*last) < interval => false,
+            Some(last) => {
+                *last = now;
+                true
+            }
+            None => {
+                last_sent.insert(topic.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// A serialized sample as it travels over the in-process bus.
+///
+/// `topic` is relative to the robot (e.g. `actuator/command`), and `payload`
+/// is the same bytes that are sent to MQTT, encoded with `encoding`.
+#[derive(Clone, Debug)]
+pub struct TelemetryRecord {
+    pub topic: String,
+    pub encoding: TelemetryEncoding,
+    pub payload: Bytes,
+}
+
+impl TelemetryRecord {
+    pub fn decode<T: TelemetryData>(&self) -> Result<TelemetryPayload<T>> {
+        self.encoding.decode(&self.payload)
+    }
 }
 
 lazy_static! {
@@ -28,37 +390,137 @@ lazy_static! {
         .unwrap_or(true);
 }
 
-#[derive(Serialize)]
-struct TelemetryPayload<T> {
-    frame_number: u64,
-    video_timestamp: u64,
-    inference_step: u64,
-    data: T,
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TelemetryPayload<T> {
+    #[serde(default)]
+    pub monotonic_ns: u64,
+    #[serde(default)]
+    pub wall_ns: u64,
+    pub frame_number: u64,
+    pub video_timestamp: u64,
+    pub inference_step: u64,
+    pub data: T,
+}
+
+impl<T> TelemetryPayload<T> {
+    pub fn new(stamp: TimeStamp, data: T) -> Self {
+        Self {
+            monotonic_ns: stamp.monotonic_ns,
+            wall_ns: stamp.wall_ns,
+            frame_number: stamp.frame_number,
+            video_timestamp: stamp.video_timestamp,
+            inference_step: stamp.inference_step,
+            data,
+        }
+    }
+
+    pub fn stamp(&self) -> TimeStamp {
+        TimeStamp {
+            monotonic_ns: self.monotonic_ns,
+            wall_ns: self.wall_ns,
+            frame_number: self.frame_number,
+            video_timestamp: self.video_timestamp,
+            inference_step: self.inference_step,
+        }
+    }
 }
 
 impl Telemetry {
-    pub async fn initialize(robot_id: &str, mqtt_host: &str, mqtt_port: u16) -> Result<()> {
-        let mut mqtt_options = MqttOptions::new(format!("kos-{}", robot_id), mqtt_host, mqtt_port);
-        mqtt_options.set_keep_alive(std::time::Duration::from_secs(5));
+    pub async fn initialize(robot_id: &str, config: TelemetryConfig) -> Result<()> {
+        let counters = Arc::new(TelemetryCounters::default());
 
-        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+        let mqtt = match config.mqtt {
+            Some(config) => {
+                let status_topic = format!("robots/{}/status", robot_id);
+                let mut mqtt_options =
+                    MqttOptions::new(format!("kos-{}", robot_id), config.host, config.port);
+                mqtt_options.set_keep_alive(std::time::Duration::from_secs(5));
+                mqtt_options.set_last_will(LastWill::new(
+                    &status_topic,
+                    STATUS_OFFLINE,
+                    QoS::AtLeastOnce,
+                    true,
+                ));
+                match (&config.username, &config.password) {
+                    (Some(username), password) => {
+                        mqtt_options
+                            .set_credentials(username, password.clone().unwrap_or_default());
+                    }
+                    (None, Some(_)) => eyre::bail!("An MQTT password needs a username"),
+                    (None, None) => {}
+                }
+                if let Some(tls) = &config.tls {
+                    mqtt_options.set_transport(tls.transport()?);
+                }
 
-        // Spawn a task to handle MQTT connection events
-        tokio::spawn(async move {
-            while let Ok(notification) = eventloop.poll().await {
-                tracing::trace!("MQTT Event: {:?}", notification);
+                let (client, eventloop) = AsyncClient::new(mqtt_options, config.queue_capacity);
+
+                let spool = match &config.spool {
+                    Some(spool_config) => match Spool::open(spool_config) {
+                        Ok(spool) => Some(spool),
+                        Err(e) => {
+                            tracing::warn!("Telemetry spool disabled: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                let sink = Arc::new(MqttSink {
+                    client,
+                    qos: config.qos,
+                    robot_id: robot_id.to_string(),
+                    counters: counters.clone(),
+                    status_topic,
+                    connected: AtomicBool::new(false),
+                    spool,
+                    replaying: AtomicBool::new(false),
+                });
+
+                tokio::spawn(run_event_loop(
+                    eventloop,
+                    sink.clone(),
+                    counters.clone(),
+                    config.min_reconnect_delay,
+                    config.max_reconnect_delay,
+                ));
+
+                Some(sink)
             }
-        });
+            None => None,
+        };
+
+        let mut sinks: Vec<Arc<dyn TelemetrySink>> = Vec::new();
+        if let Some(mqtt) = &mqtt {
+            sinks.push(mqtt.clone());
+        }
+        let influx = match config.influx {
+            Some(influx) => Some(Arc::new(InfluxSink::start(robot_id, influx).await?)),
+            None => None,
+        };
+        if let Some(influx) = &influx {
+            sinks.push(influx.clone());
+        }
+
+        let (bus, _) = broadcast::channel(TELEMETRY_BUS_CAPACITY);
 
         let telemetry = Telemetry {
-            client: Arc::new(client),
+            mqtt,
+            influx,
+            sinks,
+            rate_limiter: Arc::new(RateLimiter::new(&config.rate_limits)),
+            counters,
+            bus,
+            encoding: config.encoding,
             robot_id: robot_id.to_string(),
-            frame_number: Arc::new(Mutex::new(0)),
-            video_timestamp: Arc::new(Mutex::new(0)),
-            inference_step: Arc::new(AtomicU64::new(0)),
+            time_sync: TimeSync::global(),
         };
 
-        tracing::debug!("Initializing telemetry for robot {}", robot_id);
+        tracing::debug!(
+            "Initializing telemetry for robot {} ({})",
+            robot_id,
+            config.encoding.content_type()
+        );
         let mut global = TELEMETRY.lock().await;
         *global = Some(telemetry);
 
@@ -72,67 +534,140 @@ impl Telemetry {
         TELEMETRY.lock().await.clone()
     }
 
-    pub async fn publish<T: Serialize>(&self, topic: &str, payload: &T) -> Result<()> {
-        let telemetry_payload = TelemetryPayload {
-            frame_number: self.get_frame_number(),
-            video_timestamp: self.get_video_timestamp(),
-            inference_step: self.get_inference_step(),
-            data: payload,
+    /// Subscribes to every sample published after this call.
+    pub fn subscribe(&self) -> broadcast::Receiver<TelemetryRecord> {
+        self.bus.subscribe()
+    }
+
+    pub fn encoding(&self) -> TelemetryEncoding {
+        self.encoding
+    }
+
+    /// Publishes on the global instance if telemetry is enabled. Failures are
+    /// only logged, so a service call never fails because of telemetry.
+    pub async fn publish_event<T: TelemetryData>(topic: &str, event: &T) {
+        if let Some(telemetry) = Telemetry::get().await {
+            if let Err(e) = telemetry.try_publish(topic, event) {
+                tracing::warn!("Failed to publish telemetry: {}", e);
+            }
+        }
+    }
+
+    /// Publishes a sample. Never waits on MQTT; see `try_publish`.
+    pub async fn publish<T: TelemetryData>(&self, topic: &str, payload: &T) -> Result<()> {
+        self.try_publish(topic, payload)
+    }
+
+    /// Publishes a sample on the in-process bus and, subject to the topic's
+    /// rate limit, hands it to every external sink. If the broker is
+    /// unreachable or the MQTT queue is full the sample is spooled (or dropped
+    /// and counted when the spool is full) rather than waited on.
+    pub fn try_publish<T: TelemetryData>(&self, topic: &str, payload: &T) -> Result<()> {
+        let telemetry_payload = TelemetryPayload::new(self.time_sync.now(), payload);
+
+        let record = TelemetryRecord {
+            topic: topic.to_string(),
+            encoding: self.encoding,
+            payload: Bytes::from(self.encoding.encode(&telemetry_payload)?),
         };
 
-        let payload = serde_json::to_string(&telemetry_payload)?;
-        let full_topic = format!("robots/{}/{}", self.robot_id, topic);
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.bus.send(record.clone());
 
-        self.client
-            .publish(full_topic, QoS::AtLeastOnce, false, payload)
-            .await?;
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        if !self.rate_limiter.allow(topic) {
+            self.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        for sink in &self.sinks {
+            sink.try_send(&record);
+        }
 
         Ok(())
     }
 
-    pub fn update_frame_number(&self, new_frame_number: u64) {
-        if let Ok(mut guard) = self.frame_number.try_lock() {
-            *guard = new_frame_number;
+    /// Flushes every sink and, for MQTT, marks the robot offline.
+    pub async fn shutdown(&self) {
+        for sink in &self.sinks {
+            sink.shutdown().await;
         }
     }
 
-    pub fn update_video_timestamp(&self, new_video_timestamp: u64) {
-        if let Ok(mut guard) = self.video_timestamp.try_lock() {
-            *guard = new_video_timestamp;
+    pub fn stats(&self) -> TelemetryStats {
+        TelemetryStats {
+            connected: self
+                .mqtt
+                .as_ref()
+                .is_some_and(|mqtt| mqtt.connected.load(Ordering::Acquire)),
+            published: self.counters.published.load(Ordering::Relaxed),
+            rate_limited: self.counters.rate_limited.load(Ordering::Relaxed),
+            spooled: self.counters.spooled.load(Ordering::Relaxed),
+            replayed: self.counters.replayed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            spool_bytes: self
+                .mqtt
+                .as_ref()
+                .and_then(|mqtt| mqtt.spool.as_ref())
+                .map(Spool::bytes)
+                .unwrap_or_default(),
+            spool_errors: self
+                .mqtt
+                .as_ref()
+                .and_then(|mqtt| mqtt.spool.as_ref())
+                .map(Spool::errors)
+                .unwrap_or_default(),
+            influx_dropped: self
+                .influx
+                .as_ref()
+                .map(|influx| influx.dropped())
+                .unwrap_or_default(),
+            influx_failed: self
+                .influx
+                .as_ref()
+                .map(|influx| influx.failed())
+                .unwrap_or_default(),
         }
     }
 
+    /// The clock and counters every payload is stamped with.
+    pub fn time_sync(&self) -> &Arc<TimeSync> {
+        &self.time_sync
+    }
+
+    pub fn update_frame_number(&self, new_frame_number: u64) {
+        self.time_sync.set_frame_number(new_frame_number);
+    }
+
+    pub fn update_video_timestamp(&self, new_video_timestamp: u64) {
+        self.time_sync.set_video_timestamp(new_video_timestamp);
+    }
+
     pub fn get_frame_number(&self) -> u64 {
-        self.frame_number
-            .try_lock()
-            .map(|guard| *guard)
-            .unwrap_or(0)
+        self.time_sync.frame_number()
     }
 
     pub fn increment_frame_number(&self) {
-        if let Ok(mut guard) = self.frame_number.try_lock() {
-            *guard += 1;
-        }
+        self.time_sync.increment_frame_number();
     }
 
     pub fn get_video_timestamp(&self) -> u64 {
-        self.video_timestamp
-            .try_lock()
-            .map(|guard| *guard)
-            .unwrap_or(0)
+        self.time_sync.video_timestamp()
     }
 
     pub fn update_inference_step(&self, new_inference_step: u64) {
-        self.inference_step
-            .store(new_inference_step, Ordering::SeqCst);
+        self.time_sync.set_inference_step(new_inference_step);
     }
 
     pub fn increment_inference_step(&self) {
-        self.inference_step.fetch_add(1, Ordering::SeqCst);
+        self.time_sync.increment_inference_step();
     }
 
     pub fn get_inference_step(&self) -> u64 {
-        self.inference_step.load(Ordering::SeqCst)
+        self.time_sync.inference_step()
     }
 
     pub fn try_get() -> Option<Self> {
@@ -0,0 +1,196 @@
+//! Wire encodings for telemetry samples.
+//!
+//! JSON is the default and stays readable with any MQTT client. Protobuf
+//! wraps the sample in a `kos.telemetry.TelemetryEnvelope` built from the
+//! existing `kos_proto` messages, and CBOR is a compact binary form of the
+//! JSON structure. Binary encodings are marked by a topic suffix (see
+//! `TelemetryEncoding::topic_suffix`) so subscribers know how to decode them.
+
+use crate::kos_proto::actuator::{CommandActuatorsRequest, GetActuatorsStateResponse};
+use crate::kos_proto::telemetry::{
+    telemetry_envelope::Data, AudioEvent, ForwardEvent, LedEvent, ModelEvent, PolicyEvent,
+    TelemetryEnvelope,
+};
+use crate::telemetry::TelemetryPayload;
+use crate::telemetry_types::{ActuatorCommand, ActuatorState, EulerAngles, ImuValues, Quaternion};
+use eyre::Result;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TelemetryEncoding {
+    #[default]
+    Json,
+    Protobuf,
+    Cbor,
+}
+
+impl TelemetryEncoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TelemetryEncoding::Json => "application/json",
+            TelemetryEncoding::Protobuf => "application/x-protobuf",
+            TelemetryEncoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// Appended to MQTT topics. JSON has none, so existing subscribers keep
+    /// working unchanged.
+    pub fn topic_suffix(&self) -> &'static str {
+        match self {
+            TelemetryEncoding::Json => "",
+            TelemetryEncoding::Protobuf => ".pb",
+            TelemetryEncoding::Cbor => ".cbor",
+        }
+    }
+
+    pub fn encode<T: TelemetryData>(&self, payload: &TelemetryPayload<&T>) -> Result<Vec<u8>> {
+        Ok(match self {
+            TelemetryEncoding::Json => serde_json::to_vec(payload)?,
+            TelemetryEncoding::Protobuf => TelemetryEnvelope {
+                monotonic_ns: payload.monotonic_ns,
+                wall_ns: payload.wall_ns,
+                frame_number: payload.frame_number,
+                video_timestamp: payload.video_timestamp,
+                inference_step: payload.inference_step,
+                data: Some(payload.data.to_proto()),
+            }
+            .encode_to_vec(),
+            TelemetryEncoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(payload, &mut buf)?;
+                buf
+            }
+        })
+    }
+
+    pub fn decode<T: TelemetryData>(&self, bytes: &[u8]) -> Result<TelemetryPayload<T>> {
+        Ok(match self {
+            TelemetryEncoding::Json => serde_json::from_slice(bytes)?,
+            TelemetryEncoding::Protobuf => {
+                let envelope = TelemetryEnvelope::decode(bytes)?;
+                let data = envelope
+                    .data
+                    .and_then(T::from_proto)
+                    .ok_or_else(|| eyre::eyre!("Unexpected telemetry envelope contents"))?;
+                TelemetryPayload {
+                    monotonic_ns: envelope.monotonic_ns,
+                    wall_ns: envelope.wall_ns,
+                    frame_number: envelope.frame_number,
+                    video_timestamp: envelope.video_timestamp,
+                    inference_step: envelope.inference_step,
+                    data,
+                }
+            }
+            TelemetryEncoding::Cbor => ciborium::from_reader(bytes)?,
+        })
+    }
+}
+
+/// A sample type that can be published in every `TelemetryEncoding`.
+pub trait TelemetryData: Serialize + DeserializeOwned {
+    fn to_proto(&self) -> Data;
+    fn from_proto(data: Data) -> Option<Self>;
+}
+
+impl TelemetryData for ImuValues {
+    fn to_proto(&self) -> Data {
+        Data::ImuValues(self.into())
+    }
+
+    fn from_proto(data: Data) -> Option<Self> {
+        match data {
+            Data::ImuValues(values) => Some((&values).into()),
+            _ => None,
+        }
+    }
+}
+
+impl TelemetryData for EulerAngles {
+    fn to_proto(&self) -> Data {
+        Data::ImuEuler(self.into())
+    }
+
+    fn from_proto(data: Data) -> Option<Self> {
+        match data {
+            Data::ImuEuler(euler) => Some((&euler).into()),
+            _ => None,
+        }
+    }
+}
+
+impl TelemetryData for Quaternion {
+    fn to_proto(&self) -> Data {
+        Data::ImuQuaternion(self.into())
+    }
+
+    fn from_proto(data: Data) -> Option<Self> {
+        match data {
+            Data::ImuQuaternion(quaternion) => Some((&quaternion).into()),
+            _ => None,
+        }
+    }
+}
+
+impl TelemetryData for Vec<ActuatorState> {
+    fn to_proto(&self) -> Data {
+        Data::ActuatorStates(GetActuatorsStateResponse {
+            states: self.iter().map(Into::into).collect(),
+        })
+    }
+
+    fn from_proto(data: Data) -> Option<Self> {
+        match data {
+            Data::ActuatorStates(response) => {
+                Some(response.states.iter().map(Into::into).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl TelemetryData for Vec<ActuatorCommand> {
+    fn to_proto(&self) -> Data {
+        Data::ActuatorCommands(CommandActuatorsRequest {
+            commands: self.iter().map(Into::into).collect(),
+        })
+    }
+
+    fn from_proto(data: Data) -> Option<Self> {
+        match data {
+            Data::ActuatorCommands(request) => {
+                Some(request.commands.iter().map(Into::into).collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_event_data {
+    ($($event:ident => $variant:ident),* $(,)?) => {
+        $(
+            impl TelemetryData for $event {
+                fn to_proto(&self) -> Data {
+                    Data::$variant(self.clone())
+                }
+
+                fn from_proto(data: Data) -> Option<Self> {
+                    match data {
+                        Data::$variant(event) => Some(event),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_event_data! {
+    ModelEvent => ModelEvent,
+    ForwardEvent => ForwardEvent,
+    PolicyEvent => PolicyEvent,
+    AudioEvent => AudioEvent,
+    LedEvent => LedEvent,
+}
@@ -0,0 +1,549 @@
+//! InfluxDB line-protocol telemetry sink.
+//!
+//! Samples are taken off the publish path through a bounded channel, decoded
+//! on a background task and written in batches to a file, a UDP listener or
+//! an InfluxDB HTTP write endpoint. Each topic maps to one measurement
+//! (`imu/values` -> `imu_values`, `actuator/state` -> `actuator_state`, ...)
+//! tagged with `robot_id`, and actuator measurements get one line per
+//! actuator with an `actuator_id` tag.
+
+use crate::telemetry::{TelemetryPayload, TelemetryRecord, TelemetrySink};
+use crate::telemetry_encoding::TelemetryData;
+use crate::telemetry_types::{ActuatorCommand, ActuatorState, EulerAngles, ImuValues, Quaternion};
+use crate::time_sync;
+use async_trait::async_trait;
+use eyre::Result;
+use hyper::{Body, Client, Request};
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// Samples waiting to be written before new ones are dropped.
+const INFLUX_CHANNEL_CAPACITY: usize = 4096;
+
+/// Keeps each datagram under a typical MTU so it isn't fragmented.
+const MAX_DATAGRAM_BYTES: usize = 1400;
+
+/// Where line protocol is written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InfluxTarget {
+    /// Appends lines to a file, e.g. for `influx write --file` later.
+    File(PathBuf),
+    /// Sends lines to an InfluxDB UDP listener at `host:port`.
+    Udp(String),
+    /// POSTs batches to a write endpoint, e.g.
+    /// `http://localhost:8086/api/v2/write?org=kscale&bucket=robots&precision=ns`.
+    Http(String),
+}
+
+impl FromStr for InfluxTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = value.strip_prefix("udp://") {
+            Ok(InfluxTarget::Udp(addr.to_string()))
+        } else if value.starts_with("http://") {
+            Ok(InfluxTarget::Http(value.to_string()))
+        } else if value.starts_with("https://") {
+            Err("HTTPS is not supported; use a local proxy or the UDP listener".to_string())
+        } else if let Some(path) = value.strip_prefix("file://") {
+            Ok(InfluxTarget::File(PathBuf::from(path)))
+        } else {
+            Ok(InfluxTarget::File(PathBuf::from(value)))
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InfluxConfig {
+    pub target: InfluxTarget,
+    /// Sent as `Authorization: Token <token>` to HTTP targets.
+    pub token: Option<String>,
+    pub flush_interval: Duration,
+    /// Lines buffered before a flush is forced.
+    pub max_batch_lines: usize,
+}
+
+impl InfluxConfig {
+    pub fn new(target: InfluxTarget) -> Self {
+        Self {
+            target,
+            token: None,
+            flush_interval: Duration::from_secs(1),
+            max_batch_lines: 5000,
+        }
+    }
+}
+
+enum InfluxMessage {
+    Record(TelemetryRecord),
+    Flush(oneshot::Sender<()>),
+}
+
+pub struct InfluxSink {
+    tx: mpsc::Sender<InfluxMessage>,
+    /// Samples dropped because the writer fell behind.
+    dropped: AtomicU64,
+    /// Lines lost to failed writes.
+    failed: Arc<AtomicU64>,
+}
+
+impl InfluxSink {
+    /// Opens the target and starts the background writer.
+    pub async fn start(robot_id: &str, config: InfluxConfig) -> Result<Self> {
+        let writer = InfluxWriter::open(&config).await?;
+        let (tx, rx) = mpsc::channel(INFLUX_CHANNEL_CAPACITY);
+        let failed = Arc::new(AtomicU64::new(0));
+        tokio::spawn(run_writer(
+            robot_id.to_string(),
+            config,
+            writer,
+            rx,
+            failed.clone(),
+        ));
+        Ok(Self {
+            tx,
+            dropped: AtomicU64::new(0),
+            failed,
+        })
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for InfluxSink {
+    fn try_send(&self, record: &TelemetryRecord) {
+        if self
+            .tx
+            .try_send(InfluxMessage::Record(record.clone()))
+            .is_err()
+        {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                tracing::warn!("Dropped {} InfluxDB telemetry samples so far", dropped);
+            }
+        }
+    }
+
+    async fn shutdown(&self) {
+        let (reply, flushed) = oneshot::channel();
+        if self.tx.send(InfluxMessage::Flush(reply)).await.is_ok() {
+            let _ = tokio::time::timeout(Duration::from_secs(2), flushed).await;
+        }
+    }
+}
+
+enum InfluxWriter {
+    File(tokio::fs::File),
+    Udp(UdpSocket),
+    Http {
+        client: Client<hyper::client::HttpConnector>,
+        url: String,
+        token: Option<String>,
+    },
+}
+
+impl InfluxWriter {
+    async fn open(config: &InfluxConfig) -> Result<Self> {
+        Ok(match &config.target {
+            InfluxTarget::File(path) => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                InfluxWriter::File(
+                    tokio::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?,
+                )
+            }
+            InfluxTarget::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(addr).await?;
+                InfluxWriter::Udp(socket)
+            }
+            InfluxTarget::Http(url) => InfluxWriter::Http {
+                client: Client::new(),
+                url: url.clone(),
+                token: config.token.clone(),
+            },
+        })
+    }
+
+    async fn write(&mut self, lines: &str) -> Result<()> {
+        match self {
+            InfluxWriter::File(file) => {
+                file.write_all(lines.as_bytes()).await?;
+                file.flush().await?;
+            }
+            InfluxWriter::Udp(socket) => {
+                let mut datagram = String::new();
+                for line in lines.split_inclusive('\n') {
+                    if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM_BYTES {
+                        socket.send(datagram.as_bytes()).await?;
+                        datagram.clear();
+                    }
+                    datagram.push_str(line);
+                }
+                if !datagram.is_empty() {
+                    socket.send(datagram.as_bytes()).await?;
+                }
+            }
+            InfluxWriter::Http { client, url, token } => {
+                let mut request =
+                    Request::post(url.as_str()).header("Content-Type", "text/plain; charset=utf-8");
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Token {}", token));
+                }
+                let response = client
+                    .request(request.body(Body::from(lines.to_string()))?)
+                    .await?;
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let body = hyper::body::to_bytes(response.into_body()).await?;
+                    eyre::bail!(
+                        "InfluxDB write failed with {}: {}",
+                        status,
+                        String::from_utf8_lossy(&body)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn run_writer(
+    robot_id: String,
+    config: InfluxConfig,
+    mut writer: InfluxWriter,
+    mut rx: mpsc::Receiver<InfluxMessage>,
+    failed: Arc<AtomicU64>,
+) {
+    let mut batch = String::new();
+    let mut batch_lines = 0;
+    let mut interval = tokio::time::interval(config.flush_interval);
+
+    loop {
+        let flush_reply = tokio::select! {
+            message = rx.recv() => match message {
+                Some(InfluxMessage::Record(record)) => {
+                    batch_lines += write_record(&mut batch, &robot_id, &record);
+                    if batch_lines < config.max_batch_lines {
+                        continue;
+                    }
+                    None
+                }
+                Some(InfluxMessage::Flush(reply)) => Some(reply),
+                None => break,
+            },
+            _ = interval.tick() => None,
+        };
+
+        if !batch.is_empty() {
+            if let Err(e) = writer.write(&batch).await {
+                tracing::warn!("Failed to write {} lines to InfluxDB: {}", batch_lines, e);
+                failed.fetch_add(batch_lines as u64, Ordering::Relaxed);
+            }
+            batch.clear();
+            batch_lines = 0;
+        }
+        if let Some(reply) = flush_reply {
+            let _ = reply.send(());
+        }
+    }
+}
+
+enum FieldValue {
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+
+/// Appends one line of line protocol to `out`. Lines without any fields are
+/// skipped, since InfluxDB rejects them. Returns the number of lines written.
+fn write_line(
+    out: &mut String,
+    measurement: &str,
+    tags: &[(&str, &str)],
+    fields: &[(&str, Option<FieldValue>)],
+    timestamp_ns: u64,
+) -> usize {
+    let fields: Vec<_> = fields
+        .iter()
+        .filter_map(|(key, value)| match value {
+            Some(FieldValue::Float(v)) if !v.is_finite() => None,
+            Some(value) => Some((key, value)),
+            None => None,
+        })
+        .collect();
+    if fields.is_empty() {
+        return 0;
+    }
+
+    out.push_str(&escape(measurement, &[',', ' ']));
+    for (key, value) in tags {
+        let _ = write!(
+            out,
+            ",{}={}",
+            escape(key, &[',', '=', ' ']),
+            escape(value, &[',', '=', ' '])
+        );
+    }
+    for (i, (key, value)) in fields.iter().enumerate() {
+        out.push(if i == 0 { ' ' } else { ',' });
+        out.push_str(&escape(key, &[',', '=', ' ']));
+        out.push('=');
+        let _ = match value {
+            FieldValue::Float(v) => write!(out, "{}", v),
+            FieldValue::Int(v) => write!(out, "{}i", v),
+            FieldValue::Bool(v) => write!(out, "{}", v),
+            FieldValue::Str(v) => write!(out, "\"{}\"", escape(v, &['"', '\\'])),
+        };
+    }
+    let _ = writeln!(out, " {}", timestamp_ns);
+    1
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn float(value: impl Into<f64>) -> Option<FieldValue> {
+    Some(FieldValue::Float(value.into()))
+}
+
+fn decode<T: TelemetryData>(record: &TelemetryRecord) -> Option<TelemetryPayload<T>> {
+    record
+        .decode()
+        .map_err(|e| tracing::debug!("Failed to decode {} for InfluxDB: {}", record.topic, e))
+        .ok()
+}
+
+/// Converts a telemetry record to line protocol. Returns the number of lines
+/// written; topics without a mapping are ignored.
+fn write_record(out: &mut String, robot_id: &str, record: &TelemetryRecord) -> usize {
+    macro_rules! stamped {
+        ($payload:expr, $($field:expr),* $(,)?) => {
+            [
+                $($field,)*
+                ("frame_number", Some(FieldValue::Int($payload.frame_number as i64))),
+                ("inference_step", Some(FieldValue::Int($payload.inference_step as i64))),
+            ]
+        };
+    }
+
+    let timestamp = |wall_ns: u64| match wall_ns {
+        0 => time_sync::wall_ns(),
+        wall_ns => wall_ns,
+    };
+    let tags = [("robot_id", robot_id)];
+
+    match record.topic.as_str() {
+        "imu/values" => {
+            let Some(p) = decode::<ImuValues>(record) else {
+                return 0;
+            };
+            let v = &p.data;
+            let fields = stamped!(
+                p,
+                ("accel_x", float(v.accel_x)),
+                ("accel_y", float(v.accel_y)),
+                ("accel_z", float(v.accel_z)),
+                ("gyro_x", float(v.gyro_x)),
+                ("gyro_y", float(v.gyro_y)),
+                ("gyro_z", float(v.gyro_z)),
+                ("mag_x", v.mag_x.and_then(float)),
+                ("mag_y", v.mag_y.and_then(float)),
+                ("mag_z", v.mag_z.and_then(float)),
+                ("error", v.error.clone().map(FieldValue::Str)),
+            );
+            write_line(out, "imu_values", &tags, &fields, timestamp(p.wall_ns))
+        }
+        "imu/euler" => {
+            let Some(p) = decode::<EulerAngles>(record) else {
+                return 0;
+            };
+            let fields = stamped!(
+                p,
+                ("roll", float(p.data.roll)),
+                ("pitch", float(p.data.pitch)),
+                ("yaw", float(p.data.yaw)),
+            );
+            write_line(out, "imu_euler", &tags, &fields, timestamp(p.wall_ns))
+        }
+        "imu/quaternion" => {
+            let Some(p) = decode::<Quaternion>(record) else {
+                return 0;
+            };
+            let fields = stamped!(
+                p,
+                ("w", float(p.data.w)),
+                ("x", float(p.data.x)),
+                ("y", float(p.data.y)),
+                ("z", float(p.data.z)),
+            );
+            write_line(out, "imu_quaternion", &tags, &fields, timestamp(p.wall_ns))
+        }
+        "actuator/state" => {
+            let Some(p) = decode::<Vec<ActuatorState>>(record) else {
+                return 0;
+            };
+            p.data
+                .iter()
+                .map(|s| {
+                    let id = s.actuator_id.to_string();
+                    let fields = stamped!(
+                        p,
+                        ("online", Some(FieldValue::Bool(s.online))),
+                        ("position", s.position.and_then(float)),
+                        ("velocity", s.velocity.and_then(float)),
+                        ("torque", s.torque.and_then(float)),
+                        ("temperature", s.temperature.and_then(float)),
+                        ("voltage", s.voltage.and_then(float)),
+                        ("current", s.current.and_then(float)),
+                        ("torque_enabled", s.torque_enabled.map(FieldValue::Bool)),
+                    );
+                    write_line(
+                        out,
+                        "actuator_state",
+                        &[("robot_id", robot_id), ("actuator_id", &id)],
+                        &fields,
+                        timestamp(p.wall_ns),
+                    )
+                })
+                .sum()
+        }
+        "actuator/command" => {
+            let Some(p) = decode::<Vec<ActuatorCommand>>(record) else {
+                return 0;
+            };
+            p.data
+                .iter()
+                .map(|c| {
+                    let id = c.actuator_id.to_string();
+                    let fields = stamped!(
+                        p,
+                        ("position", c.position.and_then(float)),
+                        ("velocity", c.velocity.and_then(float)),
+                        ("torque", c.torque.and_then(float)),
+                    );
+                    write_line(
+                        out,
+                        "actuator_command",
+                        &[("robot_id", robot_id), ("actuator_id", &id)],
+                        &fields,
+                        timestamp(p.wall_ns),
+                    )
+                })
+                .sum()
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry_encoding::TelemetryEncoding;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+
+    const IMU_LINE: &str = "imu_values,robot_id=robot\\ 1 accel_x=1,accel_y=2,accel_z=3.5,\
+        gyro_x=0,gyro_y=0,gyro_z=-1,frame_number=3i,inference_step=4i 1700000000000000000\n";
+
+    fn imu_record() -> TelemetryRecord {
+        let payload = serde_json::json!({
+            "wall_ns": 1_700_000_000_000_000_000u64,
+            "frame_number": 3,
+            "video_timestamp": 0,
+            "inference_step": 4,
+            "data": {
+                "accel_x": 1.0, "accel_y": 2.0, "accel_z": 3.5,
+                "gyro_x": 0.0, "gyro_y": 0.0, "gyro_z": -1.0,
+                "mag_x": null, "mag_y": null, "mag_z": null, "error": null,
+            },
+        });
+        TelemetryRecord {
+            topic: "imu/values".to_string(),
+            encoding: TelemetryEncoding::Json,
+            payload: serde_json::to_vec(&payload).unwrap().into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_target_receives_line_protocol() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let sink = InfluxSink::start("robot 1", InfluxConfig::new(InfluxTarget::Udp(addr)))
+            .await
+            .unwrap();
+
+        sink.try_send(&imu_record());
+        sink.shutdown().await;
+
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        let len = tokio::time::timeout(Duration::from_secs(2), listener.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(), IMU_LINE);
+        assert_eq!((sink.dropped(), sink.failed()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn failed_http_writes_are_counted() {
+        let (body_tx, mut body_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let body_tx = body_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let body_tx = body_tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let _ = body_tx.send(body);
+                        let mut response = Response::new(Body::from("bucket not found"));
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/api/v2/write", server.local_addr());
+        tokio::spawn(server);
+
+        let sink = InfluxSink::start("robot 1", InfluxConfig::new(InfluxTarget::Http(url)))
+            .await
+            .unwrap();
+        sink.try_send(&imu_record());
+        sink.shutdown().await;
+
+        let body = body_rx.recv().await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), IMU_LINE);
+        assert_eq!((sink.dropped(), sink.failed()), (0, 1));
+    }
+}
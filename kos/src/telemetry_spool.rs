@@ -0,0 +1,365 @@
+//! Bounded on-disk spool for telemetry that could not be sent to MQTT.
+//!
+//! Samples are appended to `spool.bin` by a dedicated writer thread, so the
+//! publish path only pays for a channel send. When the broker is reachable
+//! again the spool is rotated to `spool.replay` and replayed in order while
+//! new samples keep going to a fresh `spool.bin`. Records are
+//! `topic_len (u16 LE) | payload_len (u32 LE) | topic | payload`; a truncated
+//! tail left by a crash is ignored.
+
+use crate::config::kos_data_dir;
+use bytes::Bytes;
+use eyre::Result;
+use rumqttc::{AsyncClient, QoS};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+const SPOOL_FILE: &str = "spool.bin";
+const REPLAY_FILE: &str = "spool.replay";
+const RECORD_PREFIX: usize = 6;
+
+/// Samples waiting for the writer thread before new ones are dropped.
+const SPOOL_CHANNEL_CAPACITY: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// Upper bound for everything on disk, including a pending replay.
+    pub max_bytes: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: kos_data_dir().join("telemetry-spool"),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+struct SpooledMessage {
+    topic: String,
+    payload: Bytes,
+}
+
+enum SpoolCommand {
+    Append(SpooledMessage),
+    /// Moves `spool.bin` to `spool.replay` unless a replay is already
+    /// pending, and reports whether there is anything to replay.
+    Rotate(oneshot::Sender<bool>),
+}
+
+#[derive(Clone)]
+pub struct Spool {
+    tx: mpsc::Sender<SpoolCommand>,
+    dir: PathBuf,
+    bytes: Arc<AtomicU64>,
+    /// Samples lost to write errors after they were queued.
+    errors: Arc<AtomicU64>,
+    max_bytes: u64,
+}
+
+fn record_len(topic: &str, payload: &[u8]) -> u64 {
+    (RECORD_PREFIX + topic.len() + payload.len()) as u64
+}
+
+impl Spool {
+    /// Opens the spool directory, picking up anything left from a previous
+    /// run, and starts the writer thread.
+    pub fn open(config: &SpoolConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        trim_partial_record(&config.dir.join(SPOOL_FILE))?;
+
+        let existing: u64 = [SPOOL_FILE, REPLAY_FILE]
+            .iter()
+            .filter_map(|name| std::fs::metadata(config.dir.join(name)).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        if existing > 0 {
+            tracing::info!("Found {} bytes of spooled telemetry", existing);
+        }
+
+        let (tx, rx) = mpsc::channel(SPOOL_CHANNEL_CAPACITY);
+        let dir = config.dir.clone();
+        let bytes = Arc::new(AtomicU64::new(existing));
+        let errors = Arc::new(AtomicU64::new(0));
+        let (writer_bytes, writer_errors) = (bytes.clone(), errors.clone());
+        std::thread::Builder::new()
+            .name("telemetry-spool".to_string())
+            .spawn(move || run_writer(dir, rx, writer_bytes, writer_errors))?;
+
+        Ok(Self {
+            tx,
+            dir: config.dir.clone(),
+            bytes,
+            errors,
+            max_bytes: config.max_bytes,
+        })
+    }
+
+    /// Queues a sample for the spool. Returns `false` if the spool is full
+    /// or the sample can't be stored in a spool record.
+    pub fn try_append(&self, topic: &str, payload: Bytes) -> bool {
+        if topic.len() > u16::MAX as usize || payload.len() > u32::MAX as usize {
+            return false;
+        }
+        let len = record_len(topic, &payload);
+        let reserved = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                (bytes + len <= self.max_bytes).then_some(bytes + len)
+            })
+            .is_ok();
+        if !reserved {
+            return false;
+        }
+
+        let message = SpooledMessage {
+            topic: topic.to_string(),
+            payload,
+        };
+        if self.tx.try_send(SpoolCommand::Append(message)).is_err() {
+            self.bytes.fetch_sub(len, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Bytes currently spooled on disk (or about to be).
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Samples lost to spool write errors since startup.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Publishes spooled samples in order until the spool is empty or the
+    /// connection drops. Returns the number of samples replayed.
+    pub async fn replay(
+        &self,
+        client: &AsyncClient,
+        qos: QoS,
+        connected: &AtomicBool,
+    ) -> Result<u64> {
+        let mut replayed = 0;
+
+        loop {
+            let (reply, pending) = oneshot::channel();
+            self.tx
+                .send(SpoolCommand::Rotate(reply))
+                .await
+                .map_err(|_| eyre::eyre!("Telemetry spool writer is not running"))?;
+            if !pending.await? {
+                return Ok(replayed);
+            }
+
+            let path = self.dir.join(REPLAY_FILE);
+            let data = tokio::fs::read(&path).await?;
+            let mut offset = 0;
+
+            while let Some((topic, payload, next)) = parse_record(&data, offset) {
+                if !connected.load(Ordering::Relaxed) {
+                    self.keep_unsent(&path, &data, offset).await?;
+                    return Ok(replayed);
+                }
+
+                let published = client
+                    .publish(topic, qos, false, Bytes::copy_from_slice(payload))
+                    .await;
+                if let Err(e) = published {
+                    self.keep_unsent(&path, &data, offset).await?;
+                    return Err(e.into());
+                }
+                offset = next;
+                replayed += 1;
+            }
+
+            tokio::fs::remove_file(&path).await?;
+            self.bytes.fetch_sub(data.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Keeps what's left of the replay file for the next connection, so
+    /// samples already sent aren't sent again.
+    async fn keep_unsent(&self, path: &Path, data: &[u8], offset: usize) -> Result<()> {
+        tokio::fs::write(path, &data[offset..]).await?;
+        self.bytes.fetch_sub(offset as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn parse_record(data: &[u8], offset: usize) -> Option<(String, &[u8], usize)> {
+    let prefix = data.get(offset..offset + RECORD_PREFIX)?;
+    let topic_len = u16::from_le_bytes([prefix[0], prefix[1]]) as usize;
+    let payload_len = u32::from_le_bytes([prefix[2], prefix[3], prefix[4], prefix[5]]) as usize;
+
+    let topic_start = offset + RECORD_PREFIX;
+    let payload_start = topic_start + topic_len;
+    let end = payload_start + payload_len;
+
+    let topic = std::str::from_utf8(data.get(topic_start..payload_start)?).ok()?;
+    let payload = data.get(payload_start..end)?;
+    Some((topic.to_string(), payload, end))
+}
+
+/// Drops a record cut short by a crash so new records don't follow garbage.
+fn trim_partial_record(path: &std::path::Path) -> Result<()> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut offset = 0;
+    while let Some((_, _, next)) = parse_record(&data, offset) {
+        offset = next;
+    }
+    if offset < data.len() {
+        tracing::warn!("Discarding partial record at the end of {}", path.display());
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(offset as u64)?;
+    }
+    Ok(())
+}
+
+/// Owns `spool.bin` on the writer thread. A failed write drops the records
+/// still buffered along with the one being written, trims the file back to
+/// its last flushed length and carries on; the file is reopened lazily.
+struct SpoolWriter {
+    spool_path: PathBuf,
+    replay_path: PathBuf,
+    file: Option<BufWriter<File>>,
+    /// Length of `spool.bin` as of the last successful flush.
+    flushed_len: u64,
+    buffered_bytes: u64,
+    buffered_records: u64,
+    bytes: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+}
+
+impl SpoolWriter {
+    fn file(&mut self) -> Result<&mut BufWriter<File>> {
+        if self.file.is_none() {
+            trim_partial_record(&self.spool_path)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.spool_path)?;
+            self.flushed_len = file.metadata()?.len();
+            self.file = Some(BufWriter::new(file));
+        }
+        self.file
+            .as_mut()
+            .ok_or_else(|| eyre::eyre!("Spool file is not open"))
+    }
+
+    fn append(&mut self, message: &SpooledMessage, flush: bool) -> Result<()> {
+        let topic_len = u16::try_from(message.topic.len())?;
+        let payload_len = u32::try_from(message.payload.len())?;
+        let file = self.file()?;
+        file.write_all(&topic_len.to_le_bytes())?;
+        file.write_all(&payload_len.to_le_bytes())?;
+        file.write_all(message.topic.as_bytes())?;
+        file.write_all(&message.payload)?;
+        self.buffered_bytes += record_len(&message.topic, &message.payload);
+        self.buffered_records += 1;
+        if flush {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        self.flushed_len += self.buffered_bytes;
+        self.buffered_bytes = 0;
+        self.buffered_records = 0;
+        Ok(())
+    }
+
+    /// Drops everything not yet flushed plus a record of `len` bytes that
+    /// failed part way, releasing their share of the spool.
+    fn discard(&mut self, len: u64) {
+        let lost_bytes = self.buffered_bytes + len;
+        let lost_records = self.buffered_records + u64::from(len > 0);
+        self.buffered_bytes = 0;
+        self.buffered_records = 0;
+
+        if let Some(file) = self.file.take() {
+            let (file, _) = file.into_parts();
+            if let Err(e) = file.set_len(self.flushed_len) {
+                tracing::warn!("Failed to trim telemetry spool: {}", e);
+            }
+        }
+        self.bytes.fetch_sub(lost_bytes, Ordering::Relaxed);
+        self.errors.fetch_add(lost_records, Ordering::Relaxed);
+    }
+
+    /// Moves `spool.bin` to `spool.replay` unless a replay is already
+    /// pending. Returns whether there is anything to replay.
+    fn rotate(&mut self) -> Result<bool> {
+        if let Err(e) = self.flush() {
+            tracing::warn!("Failed to write telemetry spool: {}", e);
+            self.discard(0);
+        }
+        if self.replay_path.exists() {
+            return Ok(true);
+        }
+        if self.file.is_none() {
+            self.file()?;
+        }
+        if self.flushed_len == 0 {
+            return Ok(false);
+        }
+        self.file = None;
+        self.flushed_len = 0;
+        std::fs::rename(&self.spool_path, &self.replay_path)?;
+        Ok(true)
+    }
+}
+
+fn run_writer(
+    dir: PathBuf,
+    mut rx: mpsc::Receiver<SpoolCommand>,
+    bytes: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+) {
+    let mut writer = SpoolWriter {
+        spool_path: dir.join(SPOOL_FILE),
+        replay_path: dir.join(REPLAY_FILE),
+        file: None,
+        flushed_len: 0,
+        buffered_bytes: 0,
+        buffered_records: 0,
+        bytes,
+        errors,
+    };
+
+    while let Some(command) = rx.blocking_recv() {
+        match command {
+            SpoolCommand::Append(message) => {
+                if let Err(e) = writer.append(&message, rx.is_empty()) {
+                    tracing::warn!("Failed to spool telemetry for {}: {}", message.topic, e);
+                    writer.discard(record_len(&message.topic, &message.payload));
+                }
+            }
+            SpoolCommand::Rotate(reply) => {
+                let pending = writer.rotate().unwrap_or_else(|e| {
+                    tracing::warn!("Failed to rotate telemetry spool: {}", e);
+                    false
+                });
+                let _ = reply.send(pending);
+            }
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use crate::grpc_interface::kos::actuator::{
     ActuatorCommand as ProtoActuatorCommand, ActuatorStateResponse,
 };
+use crate::grpc_interface::kos::common::{Error, ErrorCode};
 use crate::grpc_interface::kos::imu::{EulerAnglesResponse, ImuValuesResponse, QuaternionResponse};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ImuValues {
     pub accel_x: f64,
     pub accel_y: f64,
@@ -18,14 +19,14 @@ pub struct ImuValues {
     pub error: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct EulerAngles {
     pub roll: f64,
     pub pitch: f64,
     pub yaw: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Quaternion {
     pub x: f64,
     pub y: f64,
@@ -33,7 +34,7 @@ pub struct Quaternion {
     pub w: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ActuatorState {
     pub actuator_id: u32,
     pub online: bool,
@@ -52,7 +53,7 @@ pub struct ActuatorState {
     pub max_torque: Option<f64>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ActuatorCommand {
     pub actuator_id: u32,
     pub position: Option<f64>,
@@ -130,3 +131,80 @@ impl From<&ProtoActuatorCommand> for ActuatorCommand {
         }
     }
 }
+
+impl From<&EulerAngles> for EulerAnglesResponse {
+    fn from(euler: &EulerAngles) -> Self {
+        Self {
+            roll: euler.roll,
+            pitch: euler.pitch,
+            yaw: euler.yaw,
+            error: None,
+        }
+    }
+}
+
+impl From<&ImuValues> for ImuValuesResponse {
+    fn from(values: &ImuValues) -> Self {
+        Self {
+            accel_x: values.accel_x,
+            accel_y: values.accel_y,
+            accel_z: values.accel_z,
+            gyro_x: values.gyro_x,
+            gyro_y: values.gyro_y,
+            gyro_z: values.gyro_z,
+            mag_x: values.mag_x,
+            mag_y: values.mag_y,
+            mag_z: values.mag_z,
+            error: values.error.as_ref().map(|message| Error {
+                code: ErrorCode::Unknown as i32,
+                message: message.clone(),
+            }),
+        }
+    }
+}
+
+impl From<&Quaternion> for QuaternionResponse {
+    fn from(quaternion: &Quaternion) -> Self {
+        Self {
+            x: quaternion.x,
+            y: quaternion.y,
+            z: quaternion.z,
+            w: quaternion.w,
+            error: None,
+        }
+    }
+}
+
+impl From<&ActuatorState> for ActuatorStateResponse {
+    fn from(state: &ActuatorState) -> Self {
+        Self {
+            actuator_id: state.actuator_id,
+            online: state.online,
+            position: state.position,
+            velocity: state.velocity,
+            torque: state.torque,
+            temperature: state.temperature,
+            voltage: state.voltage,
+            current: state.current,
+            faults: vec![],
+            torque_enabled: state.torque_enabled,
+            min_position: state.min_position,
+            max_position: state.max_position,
+            kp: state.kp,
+            kd: state.kd,
+            ki: state.ki,
+            max_torque: state.max_torque,
+        }
+    }
+}
+
+impl From<&ActuatorCommand> for ProtoActuatorCommand {
+    fn from(cmd: &ActuatorCommand) -> Self {
+        Self {
+            actuator_id: cmd.actuator_id,
+            position: cmd.position,
+            velocity: cmd.velocity,
+            torque: cmd.torque,
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Helpers for the `Tensor` messages exchanged with inference backends.
+
+use crate::hal::tensor::{DataType, Dimension};
+use crate::hal::Tensor;
+use std::collections::HashMap;
+
+/// Size in bytes of one element of `raw_data`, 0 for UNSPECIFIED, which
+/// can't carry data.
+pub fn dtype_size(dtype: DataType) -> usize {
+    match dtype {
+        DataType::Unspecified => 0,
+        DataType::Int8 | DataType::Uint8 | DataType::Bool => 1,
+        DataType::Float16 | DataType::Int16 | DataType::Uint16 => 2,
+        DataType::Float32 | DataType::Int32 | DataType::Uint32 => 4,
+        DataType::Float64 | DataType::Int64 | DataType::Uint64 => 8,
+    }
+}
+
+/// The tensor's element type. Unlike the generated getter, this rejects
+/// values the enum doesn't know instead of treating them as FLOAT32, and
+/// UNSPECIFIED, which only describes model types in specs.
+pub fn dtype(tensor: &Tensor) -> Result<DataType, String> {
+    match DataType::try_from(tensor.dtype) {
+        Ok(DataType::Unspecified) => Err("Tensor dtype UNSPECIFIED can't carry data".to_string()),
+        Ok(dtype) => Ok(dtype),
+        Err(_) => Err(format!("Unknown tensor dtype {}", tensor.dtype)),
+    }
+}
+
+/// Number of elements the tensor carries, from `raw_data` if set and from
+/// `values` otherwise.
+pub fn data_len(tensor: &Tensor) -> Result<usize, String> {
+    let dtype = dtype(tensor)?;
+    if tensor.raw_data.is_empty() {
+        if dtype != DataType::Float32 && !tensor.values.is_empty() {
+            return Err(format!(
+                "values only holds FLOAT32 data, use raw_data for {}",
+                dtype.as_str_name()
+            ));
+        }
+        return Ok(tensor.values.len());
+    }
+
+    if !tensor.values.is_empty() {
+        return Err("Tensor has both values and raw_data".to_string());
+    }
+    let size = dtype_size(dtype);
+    if tensor.raw_data.len() % size != 0 {
+        return Err(format!(
+            "raw_data has {} bytes, which isn't a whole number of {} elements",
+            tensor.raw_data.len(),
+            dtype.as_str_name()
+        ));
+    }
+    Ok(tensor.raw_data.len() / size)
+}
+
+/// The tensor's elements as `f32`, from `values` or decoded from
+/// `raw_data` of any dtype. Wide integers and FLOAT64 lose precision.
+pub fn to_f32(tensor: &Tensor) -> Result<Vec<f32>, String> {
+    let dtype = dtype(tensor)?;
+    data_len(tensor)?;
+    if tensor.raw_data.is_empty() {
+        return Ok(tensor.values.clone());
+    }
+
+    let chunks = tensor.raw_data.chunks_exact(dtype_size(dtype));
+    Ok(chunks
+        .map(|b| match dtype {
+            // Rejected by `dtype` above.
+            DataType::Unspecified => f32::NAN,
+            DataType::Bool | DataType::Uint8 => b[0] as f32,
+            DataType::Int8 => b[0] as i8 as f32,
+            DataType::Float16 => f16_to_f32(u16::from_le_bytes([b[0], b[1]])),
+            DataType::Int16 => i16::from_le_bytes([b[0], b[1]]) as f32,
+            DataType::Uint16 => u16::from_le_bytes([b[0], b[1]]) as f32,
+            DataType::Float32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            DataType::Int32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            DataType::Uint32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            DataType::Float64 => f64::from_le_bytes(le_bytes_8(b)) as f32,
+            DataType::Int64 => i64::from_le_bytes(le_bytes_8(b)) as f32,
+            DataType::Uint64 => u64::from_le_bytes(le_bytes_8(b)) as f32,
+        })
+        .collect())
+}
+
+fn le_bytes_8(b: &[u8]) -> [u8; 8] {
+    [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]
+}
+
+/// Converts IEEE 754 half-precision bits to `f32`.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        e => (1.0 + mantissa / 1024.0) * 2f32.powi(e as i32 - 15),
+    }
+}
+
+/// Moves FLOAT32 `values` into `raw_data`, for clients that asked for raw
+/// outputs. Tensors already in `raw_data` are returned unchanged.
+pub fn into_raw(mut tensor: Tensor) -> Tensor {
+    if tensor.raw_data.is_empty() && !tensor.values.is_empty() {
+        tensor.raw_data = tensor
+            .values
+            .drain(..)
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        tensor.dtype = DataType::Float32 as i32;
+    }
+    tensor
+}
+
+/// Formats a shape as e.g. `[batch, 48]`, using the name of dynamic dims.
+pub fn describe_shape(shape: &[Dimension]) -> String {
+    let dims: Vec<String> = shape
+        .iter()
+        .map(|dim| {
+            if dim.dynamic {
+                if dim.name.is_empty() {
+                    "?".to_string()
+                } else {
+                    dim.name.clone()
+                }
+            } else {
+                dim.size.to_string()
+            }
+        })
+        .collect();
+    format!("[{}]", dims.join(", "))
+}
+
+/// Number of elements a tensor of this shape holds.
+pub fn element_count(shape: &[Dimension]) -> usize {
+    shape.iter().map(|dim| dim.size as usize).product()
+}
+
+/// The concrete shape of `spec`, with dynamic dims sized from `dim_sizes`
+/// by name, or 1.
+fn concrete_shape(spec: &Tensor, dim_sizes: &HashMap<String, u32>) -> Vec<Dimension> {
+    spec.shape
+        .iter()
+        .map(|dim| Dimension {
+            size: if dim.dynamic {
+                dim_sizes.get(&dim.name).copied().unwrap_or(1)
+            } else {
+                dim.size
+            },
+            name: dim.name.clone(),
+            dynamic: false,
+        })
+        .collect()
+}
+
+/// A zeroed tensor matching `spec`, e.g. the initial state of a recurrent
+/// model. Dynamic dims take their size from `dim_sizes` by name, or 1.
+pub fn zeros(spec: &Tensor, dim_sizes: &HashMap<String, u32>) -> Tensor {
+    let shape = concrete_shape(spec, dim_sizes);
+    let count = element_count(&shape);
+    match spec.dtype() {
+        DataType::Float32 => Tensor {
+            values: vec![0.0; count],
+            shape,
+            dtype: DataType::Float32 as i32,
+            raw_data: vec![],
+        },
+        dtype => Tensor {
+            values: vec![],
+            shape,
+            dtype: dtype as i32,
+            raw_data: vec![0; count * dtype_size(dtype)],
+        },
+    }
+}
+
+/// Builds an input matching `spec`, for benchmarking. Dynamic dims are
+/// sized as in `zeros`. FLOAT32 inputs get a fixed pattern in [-0.5, 0.5)
+/// rather than zeros, so kernels with data-dependent shortcuts aren't
+/// flattered; other types are zeroed.
+pub fn synthetic_input(spec: &Tensor, dim_sizes: &HashMap<String, u32>) -> Tensor {
+    let mut tensor = zeros(spec, dim_sizes);
+    for (i, value) in tensor.values.iter_mut().enumerate() {
+        *value = (i * 7919 % 1000) as f32 / 1000.0 - 0.5;
+    }
+    tensor
+}
+
+/// Checks `inputs` against a model's input specs: every declared input is
+/// present and nothing else, ranks match, static dims have their declared
+/// size, dynamic dims sharing a name (e.g. `batch`) agree across inputs, and
+/// each tensor holds as many elements as its shape says, in `values` or
+/// `raw_data`.
+///
+/// A tensor sent without a shape is taken as a flat vector, which is only
+/// accepted for rank-1 inputs. Returns a description of the first problem.
+pub fn validate_inputs(
+    specs: &HashMap<String, Tensor>,
+    inputs: &HashMap<String, Tensor>,
+) -> Result<(), String> {
+    let mut missing: Vec<_> = specs
+        .keys()
+        .filter(|name| !inputs.contains_key(*name))
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(format!("Missing input tensors {:?}", missing));
+    }
+    let mut unknown: Vec<_> = inputs
+        .keys()
+        .filter(|name| !specs.contains_key(*name))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        let mut expected: Vec<_> = specs.keys().collect();
+        expected.sort();
+        return Err(format!(
+            "Unknown input tensors {:?}, the model takes {:?}",
+            unknown, expected
+        ));
+    }
+
+    // Sorted so the reported error doesn't depend on map order.
+    let mut names: Vec<_> = specs.keys().collect();
+    names.sort();
+
+    // (dim name, size, input, axis) of every named dynamic dim, checked for
+    // agreement once each input is known to be well-formed on its own.
+    let mut dynamic_dims: Vec<(&str, u32, &str, usize)> = Vec::new();
+    for name in names {
+        let spec = &specs[name];
+        let input = &inputs[name];
+        if spec.dtype == DataType::Unspecified as i32 {
+            return Err(format!(
+                "Input '{}' has an element type that can't be sent to the model",
+                name
+            ));
+        }
+        let expected = describe_shape(&spec.shape);
+        let len = data_len(input).map_err(|e| format!("Input '{}': {}", name, e))?;
+
+        if input.shape.is_empty() {
+            if spec.shape.len() != 1 {
+                return Err(format!(
+                    "Input '{}' has no shape, expected {}",
+                    name, expected
+                ));
+            }
+            let dim = &spec.shape[0];
+            if !dim.dynamic && len != dim.size as usize {
+                return Err(format!(
+                    "Input '{}' has {} elements, expected {}",
+                    name, len, expected
+                ));
+            }
+            continue;
+        }
+
+        if input.shape.len() != spec.shape.len() {
+            return Err(format!(
+                "Input '{}' has rank {} ({}), expected rank {} ({})",
+                name,
+                input.shape.len(),
+                describe_shape(&input.shape),
+                spec.shape.len(),
+                expected
+            ));
+        }
+
+        for (axis, (dim, spec_dim)) in input.shape.iter().zip(&spec.shape).enumerate() {
+            if !spec_dim.dynamic {
+                if dim.size != spec_dim.size {
+                    return Err(format!(
+                        "Input '{}' has size {} on axis {}, expected {} ({})",
+                        name, dim.size, axis, spec_dim.size, expected
+                    ));
+                }
+                continue;
+            }
+            if !spec_dim.name.is_empty() {
+                dynamic_dims.push((&spec_dim.name, dim.size, name, axis));
+            }
+        }
+
+        let count = element_count(&input.shape);
+        if len != count {
+            return Err(format!(
+                "Input '{}' has {} elements, but shape {} holds {}",
+                name,
+                len,
+                describe_shape(&input.shape),
+                count
+            ));
+        }
+    }
+
+    let mut sizes: HashMap<&str, (u32, &str)> = HashMap::new();
+    for (dim_name, size, name, axis) in dynamic_dims {
+        match sizes.get(dim_name) {
+            Some(&(other_size, other)) if other_size != size => {
+                return Err(format!(
+                    "Input '{}' has {} = {} on axis {}, but input '{}' has {} = {}",
+                    name, dim_name, size, axis, other, dim_name, other_size
+                ));
+            }
+            Some(_) => {}
+            None => {
+                sizes.insert(dim_name, (size, name));
+            }
+        }
+    }
+
+    Ok(())
+}
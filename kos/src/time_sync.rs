@@ -0,0 +1,111 @@
+//! Process-wide clock and counters shared by telemetry and recordings.
+//!
+//! Every telemetry payload and KRec frame is stamped from the same
+//! `TimeSync`, so samples from different services line up without each one
+//! calling `SystemTime::now()` on its own.
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref TIME_SYNC: Arc<TimeSync> = Arc::new(TimeSync::new());
+}
+
+/// A consistent snapshot of the clocks and counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeStamp {
+    /// Nanoseconds since the process started; never goes backwards.
+    pub monotonic_ns: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub wall_ns: u64,
+    pub frame_number: u64,
+    pub video_timestamp: u64,
+    pub inference_step: u64,
+}
+
+pub struct TimeSync {
+    start: Instant,
+    frame_number: AtomicU64,
+    video_timestamp: AtomicU64,
+    inference_step: AtomicU64,
+}
+
+impl Default for TimeSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSync {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frame_number: AtomicU64::new(0),
+            video_timestamp: AtomicU64::new(0),
+            inference_step: AtomicU64::new(0),
+        }
+    }
+
+    /// The instance shared by the whole process.
+    pub fn global() -> Arc<TimeSync> {
+        TIME_SYNC.clone()
+    }
+
+    pub fn now(&self) -> TimeStamp {
+        TimeStamp {
+            monotonic_ns: self.monotonic_ns(),
+            wall_ns: wall_ns(),
+            frame_number: self.frame_number(),
+            video_timestamp: self.video_timestamp(),
+            inference_step: self.inference_step(),
+        }
+    }
+
+    pub fn monotonic_ns(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number.load(Ordering::Acquire)
+    }
+
+    pub fn set_frame_number(&self, frame_number: u64) {
+        self.frame_number.store(frame_number, Ordering::Release);
+    }
+
+    pub fn increment_frame_number(&self) -> u64 {
+        self.frame_number.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub fn video_timestamp(&self) -> u64 {
+        self.video_timestamp.load(Ordering::Acquire)
+    }
+
+    pub fn set_video_timestamp(&self, video_timestamp: u64) {
+        self.video_timestamp
+            .store(video_timestamp, Ordering::Release);
+    }
+
+    pub fn inference_step(&self) -> u64 {
+        self.inference_step.load(Ordering::Acquire)
+    }
+
+    pub fn set_inference_step(&self, inference_step: u64) {
+        self.inference_step.store(inference_step, Ordering::Release);
+    }
+
+    pub fn increment_inference_step(&self) -> u64 {
+        self.inference_step.fetch_add(1, Ordering::AcqRel) + 1
+    }
+}
+
+/// Current wall-clock time in nanoseconds since the Unix epoch.
+pub fn wall_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}